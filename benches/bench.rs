@@ -1,5 +1,11 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledStore};
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsServer, NaiveThreadPool, RayonThreadPool,
+    SharedQueueThreadPool, SledStore, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn bench_kv_set(c: &mut Criterion) {
@@ -84,11 +90,76 @@ fn bench_sled_get(c: &mut Criterion) {
     });
 }
 
+const CLIENTS: usize = 8;
+const CLIENT_KEYS: usize = 100;
+
+/// Runs `CLIENTS` clients at once against a server on a `P` pool, for each pool
+/// size. Each client sets then gets its keys on its own connection.
+fn bench_pool<P: ThreadPool + Send + 'static>(c: &mut Criterion, name: &str, port: u16) {
+    c.bench_function_over_inputs(
+        name,
+        move |b, &threads| {
+            let addr = format!("127.0.0.1:{}", port);
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
+            let pool = P::new(threads).unwrap();
+            let log = Logger::root(Discard, o!());
+            let mut server = KvsServer::new(addr.clone(), store, log, pool).unwrap();
+            let handle = server.shutdown_handle();
+            let server = thread::spawn(move || server.start());
+            while KvsClient::connect(&addr).is_err() {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            b.iter(|| {
+                let clients: Vec<_> = (0..CLIENTS)
+                    .map(|client| {
+                        let addr = addr.clone();
+                        thread::spawn(move || {
+                            let mut kvs_client = KvsClient::connect(&addr).unwrap();
+                            for i in 0..CLIENT_KEYS {
+                                let key = format!("key{}-{}", client, i);
+                                kvs_client.set(&key, "value").unwrap();
+                            }
+                            for i in 0..CLIENT_KEYS {
+                                let key = format!("key{}-{}", client, i);
+                                assert_eq!(kvs_client.get(&key).unwrap().unwrap(), "value");
+                            }
+                        })
+                    })
+                    .collect();
+                for client in clients {
+                    client.join().unwrap();
+                }
+            });
+
+            handle.shutdown();
+            server.join().unwrap().unwrap();
+        },
+        vec![1, 2, 4, 8],
+    );
+}
+
+fn bench_naive_pool(c: &mut Criterion) {
+    bench_pool::<NaiveThreadPool>(c, "Naive pool server", 4101);
+}
+
+fn bench_shared_queue_pool(c: &mut Criterion) {
+    bench_pool::<SharedQueueThreadPool>(c, "Shared queue pool server", 4102);
+}
+
+fn bench_rayon_pool(c: &mut Criterion) {
+    bench_pool::<RayonThreadPool>(c, "Rayon pool server", 4103);
+}
+
 criterion_group!(
     benches,
     bench_kv_set,
     bench_kv_get,
     bench_sled_set,
-    bench_sled_get
+    bench_sled_get,
+    bench_naive_pool,
+    bench_shared_queue_pool,
+    bench_rayon_pool
 );
 criterion_main!(benches);
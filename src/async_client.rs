@@ -0,0 +1,186 @@
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use crate::client::{
+    cas_response, pairs_response, require_feature, string_outcome, string_pairs, string_value,
+    unit_response, value_response,
+};
+use crate::common::{
+    read_frame_async, write_frame_async, Command, Hello, HelloReply, KeyRange, Response,
+};
+use crate::engines::deadline_after;
+use crate::{CasOutcome, KvsError, Result, ScanOptions, WriteBatch};
+
+/// The async counterpart of `KvsClient`, for use on a tokio runtime. It talks
+/// to either `KvsServer` or `AsyncKvsServer`.
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    features: Vec<String>,
+}
+
+impl AsyncKvsClient {
+    /// Connects to a server and agrees with it on the protocol version and
+    /// features to use. Fails if the server cannot serve this client.
+    pub async fn connect(addr: &str) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        write_frame_async(&mut writer, &Hello::current()).await?;
+        writer.flush().await?;
+        let reply: HelloReply = read_frame_async(&mut reader)
+            .await?
+            .ok_or(KvsError::ConnectionClosedError)?;
+        Ok(AsyncKvsClient {
+            reader,
+            writer,
+            features: reply.features()?,
+        })
+    }
+
+    /// Returns `true` if both this client and the server support `feature`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    pub async fn send_command(&mut self, cmd: Command) -> Result<Response> {
+        require_feature(&self.features, &cmd)?;
+        write_frame_async(&mut self.writer, &cmd).await?;
+        self.writer.flush().await?;
+        match read_frame_async(&mut self.reader).await? {
+            Some(res) => Ok(res),
+            None => Err(KvsError::ConnectionClosedError),
+        }
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>> {
+        string_value(self.get_bytes(key.as_bytes()).await?)
+    }
+
+    /// Returns the value of a key, byte for byte.
+    pub async fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        value_response(self.send_command(Command::Get(key.to_vec())).await?)
+    }
+
+    pub async fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes()).await
+    }
+
+    /// Sets a key to a binary value.
+    pub async fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        unit_response(
+            self.send_command(Command::Set(key.to_vec(), value.to_vec()))
+                .await?,
+        )
+    }
+
+    /// Sets a key that expires after `ttl`.
+    pub async fn set_with_ttl(&mut self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+            .await
+    }
+
+    /// Sets a key to a binary value that expires after `ttl`.
+    pub async fn set_bytes_with_ttl(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<()> {
+        let cmd = Command::SetExpiring(key.to_vec(), value.to_vec(), deadline_after(ttl));
+        unit_response(self.send_command(cmd).await?)
+    }
+
+    pub async fn remove(&mut self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes()).await
+    }
+
+    /// Removes a binary key.
+    pub async fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        unit_response(self.send_command(Command::Rm(key.to_vec())).await?)
+    }
+
+    /// Applies all the writes in `batch` atomically.
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        unit_response(self.send_command(Command::Batch(batch)).await?)
+    }
+
+    /// Sets `key` to `new` if its current value is `expected`, where `None` stands
+    /// for a missing key on both sides.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<&str>,
+    ) -> Result<CasOutcome> {
+        let outcome = self
+            .compare_and_swap_bytes(
+                key.as_bytes(),
+                expected.map(str::as_bytes),
+                new.map(str::as_bytes),
+            )
+            .await?;
+        string_outcome(outcome)
+    }
+
+    /// Swaps binary values, see `compare_and_swap`.
+    pub async fn compare_and_swap_bytes(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        let cmd = Command::Cas(
+            key.to_vec(),
+            expected.map(<[u8]>::to_vec),
+            new.map(<[u8]>::to_vec),
+        );
+        cas_response(self.send_command(cmd).await?)
+    }
+
+    /// Returns the pairs with keys from `start`, inclusive, to `end`, exclusive.
+    /// A missing bound leaves that side of the range open.
+    pub async fn scan(
+        &mut self,
+        start: Option<&str>,
+        end: Option<&str>,
+        options: ScanOptions,
+    ) -> Result<Vec<(String, String)>> {
+        let pairs = self
+            .scan_bytes(start.map(str::as_bytes), end.map(str::as_bytes), options)
+            .await?;
+        string_pairs(pairs)
+    }
+
+    /// Returns the binary pairs with keys from `start`, inclusive, to `end`, exclusive.
+    pub async fn scan_bytes(
+        &mut self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        options: ScanOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = KeyRange::Range(start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec));
+        pairs_response(self.send_command(Command::Scan(range, options)).await?)
+    }
+
+    /// Returns the pairs with keys starting with `prefix`.
+    pub async fn scan_prefix(
+        &mut self,
+        prefix: &str,
+        options: ScanOptions,
+    ) -> Result<Vec<(String, String)>> {
+        string_pairs(self.scan_prefix_bytes(prefix.as_bytes(), options).await?)
+    }
+
+    /// Returns the binary pairs with keys starting with `prefix`.
+    pub async fn scan_prefix_bytes(
+        &mut self,
+        prefix: &[u8],
+        options: ScanOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = KeyRange::Prefix(prefix.to_vec());
+        pairs_response(self.send_command(Command::Scan(range, options)).await?)
+    }
+}
@@ -0,0 +1,90 @@
+use crate::common::{answer_hello, read_frame_async, write_frame_async};
+use crate::server::handle_command;
+use crate::{KvsEngine, KvsError, Result};
+use slog::Logger;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tokio::time;
+
+/// How long to wait before accepting again after a failure, which is most
+/// likely the process running out of file descriptors.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A `KvsServer` for the `KvsClient` protocol that runs on a tokio runtime, so
+/// idle connections do not hold a thread. Engine calls block, so they run on
+/// the runtime's blocking threads.
+pub struct AsyncKvsServer<T: KvsEngine> {
+    addr: String,
+    log: Logger,
+    store: T,
+}
+
+impl<T: KvsEngine> AsyncKvsServer<T> {
+    pub fn new(addr: String, store: T, log: Logger) -> Self {
+        AsyncKvsServer { addr, store, log }
+    }
+
+    /// Accepts and serves connections. Only fails if the address cannot be
+    /// bound, as a failure to accept a connection is logged and retried.
+    pub async fn run(self) -> Result<()> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Connections already open keep being served meanwhile,
+                    // and closing them frees descriptors to accept more.
+                    error!(self.log, "Error while accepting connection: {}", e);
+                    time::sleep(ACCEPT_RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+            info!(self.log, "New connection"; "client addr" => peer_addr);
+            let log = self.log.clone();
+            let store = self.store.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(store, &log, stream).await {
+                    error!(log, "Error while handling connection: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Serves the commands sent on a connection, in order, until the client closes it.
+async fn handle_connection<T: KvsEngine>(
+    mut store: T,
+    log: &Logger,
+    stream: TcpStream,
+) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let reply = match answer_hello(read_frame_async(&mut reader).await)? {
+        Some(reply) => reply,
+        None => return Ok(()),
+    };
+    write_frame_async(&mut writer, &reply).await?;
+    writer.flush().await?;
+    reply.features()?;
+    while let Some(cmd) = read_frame_async(&mut reader).await? {
+        // The handle comes back with the result, so the files it opened stay
+        // open for the next command.
+        let cmd_log = log.clone();
+        let (res, handle) = task::spawn_blocking(move || {
+            let res = handle_command(&store, &cmd_log, cmd);
+            (res, store)
+        })
+        .await
+        .map_err(|e| KvsError::Err(format!("Command handler failed: {}", e)))?;
+        store = handle;
+        write_frame_async(&mut writer, &res).await?;
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    debug!(log, "Connection closed by client");
+    Ok(())
+}
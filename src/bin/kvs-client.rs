@@ -1,5 +1,6 @@
 extern crate structopt;
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, Result, ScanOptions};
+use std::time::Duration;
 use std::{env, process};
 use structopt::StructOpt;
 
@@ -18,13 +19,41 @@ enum Cmd {
     #[structopt(name = "get")]
     Get { key: String },
     #[structopt(name = "set")]
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        /// Expires the key after this long, e.g. 30s, 5m or 500ms
+        #[structopt(long = "ttl", parse(try_from_str = "parse_ttl"))]
+        ttl: Option<Duration>,
+    },
     #[structopt(name = "rm")]
     Remove { key: String },
+    /// Lists the pairs with keys from START up to, but excluding, END
+    #[structopt(name = "scan")]
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+        /// Only lists keys starting with this prefix, instead of a range
+        #[structopt(long = "prefix", raw(conflicts_with_all = r#"&["start", "end"]"#))]
+        prefix: Option<String>,
+        /// Lists at most this many pairs
+        #[structopt(long)]
+        limit: Option<usize>,
+        /// Lists pairs in descending key order
+        #[structopt(long)]
+        reverse: bool,
+    },
 }
 
-fn main() -> Result<()> {
+fn main() {
     let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
     if opt.version {
         println!(env!("CARGO_PKG_VERSION"));
         return Ok(());
@@ -43,13 +72,61 @@ fn main() -> Result<()> {
                 }
                 Err(e) => Err(e),
             },
-            Cmd::Set { key, value } => client.set(&key, &value),
+            Cmd::Set { key, value, ttl } => match ttl {
+                Some(ttl) => client.set_with_ttl(&key, &value, ttl),
+                None => client.set(&key, &value),
+            },
             Cmd::Remove { key } => match client.remove(&key) {
                 Ok(_) => Ok(()),
                 Err(e) => Err(e),
             },
+            Cmd::Scan {
+                start,
+                end,
+                prefix,
+                limit,
+                reverse,
+            } => {
+                let mut options = ScanOptions::new();
+                if let Some(limit) = limit {
+                    options = options.limit(limit);
+                }
+                if reverse {
+                    options = options.reverse();
+                }
+                let pairs = match prefix {
+                    Some(prefix) => client.scan_prefix(&prefix, options)?,
+                    None => client.scan(start.as_deref(), end.as_deref(), options)?,
+                };
+                for (key, value) in pairs {
+                    println!("{} {}", key, value);
+                }
+                Ok(())
+            }
         }
     } else {
         process::exit(1);
     }
 }
+
+/// Parses a duration made of a number and a unit of `ms`, `s`, `m` or `h`.
+fn parse_ttl(s: &str) -> std::result::Result<Duration, String> {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(unit_start);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid TTL {}, expected e.g. 30s", s))?;
+    let secs = |multiplier: u64| {
+        amount
+            .checked_mul(multiplier)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("TTL {} is too long", s))
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "s" => Ok(Duration::from_secs(amount)),
+        "m" => secs(60),
+        "h" => secs(60 * 60),
+        _ => Err(format!("Invalid TTL unit in {}, expected ms, s, m or h", s)),
+    }
+}
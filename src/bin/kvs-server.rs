@@ -6,7 +6,9 @@ extern crate slog_term;
 #[macro_use]
 extern crate clap;
 use kvs::{
-    KvStore, KvsEngine, KvsError, KvsServer, NaiveThreadPool, Result, SledStore, ThreadPool,
+    AsyncKvsServer, BusyPolicy, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer,
+    NaiveThreadPool, Protocol, RayonThreadPool, Result, SharedQueueThreadPool, SledStore,
+    SyncPolicy, ThreadPool,
 };
 use slog::Drain;
 use std::env;
@@ -14,6 +16,7 @@ use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::process;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -24,6 +27,58 @@ struct Opt {
     addr: String,
     #[structopt(long)]
     engine: Option<Engine>,
+    /// Stale bytes in the kvs log before it is compacted
+    #[structopt(long = "compaction-threshold")]
+    compaction_threshold: Option<u64>,
+    /// Minimum fraction of the kvs log that must be stale before it is compacted
+    #[structopt(long = "compaction-ratio")]
+    compaction_ratio: Option<f64>,
+    /// Size in bytes after which the kvs engine starts a new log file
+    #[structopt(long = "max-file-size")]
+    max_file_size: Option<u64>,
+    /// When the kvs engine fsyncs writes: never, always or every N milliseconds
+    #[structopt(long = "sync", default_value = "never")]
+    sync: SyncPolicy,
+    /// The protocol clients speak: kvs, or resp for Redis clients
+    #[structopt(long = "protocol", default_value = "kvs")]
+    protocol: Protocol,
+    /// Also serves an HTTP gateway to the store on this address
+    #[structopt(long = "http-addr")]
+    http_addr: Option<String>,
+    /// Serves connections on an async runtime instead of a thread each. Only
+    /// the kvs protocol is served this way
+    #[structopt(long = "async")]
+    asynchronous: bool,
+    /// The thread pool connections are served on: naive, shared or rayon
+    #[structopt(long = "pool", default_value = "naive")]
+    pool: Pool,
+    /// Number of threads in the shared and rayon pools
+    #[structopt(long = "threads", default_value = "4")]
+    threads: usize,
+    /// Bounds the connections waiting for a thread. The naive pool then runs
+    /// at most this many more than --threads
+    #[structopt(long = "queue-len")]
+    queue_len: Option<usize>,
+    /// What to do with new connections once the queue is full: block, reject,
+    /// or shed-oldest to turn away the client that waited longest
+    #[structopt(long = "busy", default_value = "block")]
+    busy_policy: BusyPolicy,
+}
+
+impl Opt {
+    fn store_options(&self) -> KvStoreOptions {
+        let mut options = KvStoreOptions::new().sync_policy(self.sync);
+        if let Some(threshold) = self.compaction_threshold {
+            options = options.compaction_threshold(threshold);
+        }
+        if let Some(ratio) = self.compaction_ratio {
+            options = options.compaction_ratio(ratio);
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            options = options.max_file_size(max_file_size);
+        }
+        options
+    }
 }
 
 arg_enum! {
@@ -52,6 +107,16 @@ impl Engine {
     }
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Pool {
+        naive,
+        shared,
+        rayon
+    }
+}
+
 const DEFAULT_ENGINE: Engine = Engine::kvs;
 
 fn main() -> Result<()> {
@@ -90,23 +155,75 @@ fn main() -> Result<()> {
     };
     match engine {
         Engine::kvs => {
-            let store = KvStore::open(&env::current_dir()?.as_path())?;
+            let store = KvStore::open_with(env::current_dir()?.as_path(), opt.store_options())?;
             log = log.new(o!("engine" => "kvs"));
-            start_server(store, opt.addr, log.clone())?;
+            start_server(store, &opt, log.clone())?;
         }
         Engine::sled => {
             let store = SledStore::open(&env::current_dir()?.as_path())?;
             log = log.new(o!("engine" => "sled"));
-            start_server(store, opt.addr, log.clone())?;
+            start_server(store, &opt, log.clone())?;
         }
     }
     Ok(())
 }
 
-fn start_server<T: KvsEngine>(store: T, addr: String, log: slog::Logger) -> Result<()> {
-    info!(log, "Starting server");
-    let pool = NaiveThreadPool::new(4)?;
-    KvsServer::new(addr, store, log, pool)?.start()
+fn start_server<T: KvsEngine>(store: T, opt: &Opt, log: slog::Logger) -> Result<()> {
+    info!(log, "Starting server"; "protocol" => format!("{:?}", opt.protocol));
+    if opt.asynchronous {
+        if opt.protocol != Protocol::Kvs || opt.http_addr.is_some() {
+            error!(log, "The async server only serves the kvs protocol");
+            return Err(KvsError::Err("Unsupported async server options".into()));
+        }
+        // The async server cannot be stopped, so on a signal only the engine
+        // is flushed before exiting.
+        let flushed = store.clone();
+        let signal_log = log.clone();
+        set_signal_handler(move || {
+            if let Err(e) = flushed.flush() {
+                error!(signal_log, "Error while flushing the engine: {}", e);
+            }
+            process::exit(0);
+        })?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        return runtime.block_on(AsyncKvsServer::new(opt.addr.clone(), store, log).run());
+    }
+    let pool = format!("{:?}", opt.pool);
+    info!(log, "Starting thread pool"; "pool" => pool, "threads" => opt.threads);
+    match opt.pool {
+        Pool::naive => serve(store, new_pool::<NaiveThreadPool>(opt)?, opt, log),
+        Pool::shared => serve(store, new_pool::<SharedQueueThreadPool>(opt)?, opt, log),
+        Pool::rayon => serve(store, new_pool::<RayonThreadPool>(opt)?, opt, log),
+    }
+}
+
+fn new_pool<P: ThreadPool>(opt: &Opt) -> Result<P> {
+    match opt.queue_len {
+        Some(queue_len) => P::bounded(opt.threads, queue_len),
+        None => P::new(opt.threads),
+    }
+}
+
+fn serve<T: KvsEngine, P: ThreadPool>(
+    store: T,
+    pool: P,
+    opt: &Opt,
+    log: slog::Logger,
+) -> Result<()> {
+    let mut server = KvsServer::new(opt.addr.clone(), store, log, pool)?
+        .protocol(opt.protocol)
+        .busy_policy(opt.busy_policy);
+    if let Some(http_addr) = &opt.http_addr {
+        server = server.http_addr(http_addr.clone());
+    }
+    let handle = server.shutdown_handle();
+    set_signal_handler(move || handle.shutdown())?;
+    server.start()
+}
+
+/// Runs `handler` on SIGINT and SIGTERM.
+fn set_signal_handler<F: FnMut() + Send + 'static>(handler: F) -> Result<()> {
+    ctrlc::set_handler(handler).map_err(|e| KvsError::Err(e.to_string()))
 }
 
 fn get_current_engine() -> Result<Option<String>> {
@@ -1,48 +1,334 @@
-use serde_json;
 use std::io::prelude::*;
-use std::net::{Shutdown, TcpStream};
+use std::io::{BufReader, BufWriter};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
 
-use crate::common::{Command, Response};
-use crate::{KvsError, Result};
+use crate::common::{
+    read_frame, response_error, write_frame, Command, Hello, HelloReply, KeyRange, Response,
+};
+use crate::engines::deadline_after;
+use crate::{CasOutcome, KvsError, Result, ScanOptions, WriteBatch};
 
+/// A connection to a `KvsServer`. It stays open for any number of commands,
+/// which are sent one at a time.
 pub struct KvsClient {
-    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    features: Vec<String>,
 }
 
 impl KvsClient {
+    /// Connects to a server and agrees with it on the protocol version and
+    /// features to use. Fails if the server cannot serve this client.
     pub fn connect(addr: &str) -> Result<Self> {
-        let stream = TcpStream::connect(&addr)?;
-        let client = KvsClient { stream: stream };
-        Ok(client)
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        write_frame(&mut writer, &Hello::current())?;
+        writer.flush()?;
+        let reply: HelloReply = read_frame(&mut reader)?.ok_or(KvsError::ConnectionClosedError)?;
+        Ok(KvsClient {
+            reader,
+            writer,
+            features: reply.features()?,
+        })
+    }
+
+    /// Returns `true` if both this client and the server support `feature`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
     }
 
     pub fn send_command(&mut self, cmd: Command) -> Result<Response> {
-        serde_json::to_writer(&mut self.stream, &cmd)?;
-        self.stream.flush()?;
-        self.stream.shutdown(Shutdown::Write)?;
-        let res = serde_json::from_reader(&self.stream)?;
-        Ok(res)
+        require_feature(&self.features, &cmd)?;
+        write_frame(&mut self.writer, &cmd)?;
+        self.writer.flush()?;
+        match read_frame(&mut self.reader)? {
+            Some(res) => Ok(res),
+            None => Err(KvsError::ConnectionClosedError),
+        }
     }
 
-    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
-        let res = self.send_command(Command::Get(key.to_owned()))?;
-        match res {
-            Response::Ok(value) => Ok(value),
-            Response::Err(error) => Err(KvsError::Err(error)),
+    /// Returns a pipeline that sends queued commands on this connection without
+    /// waiting for each response.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            cmds: Vec::new(),
         }
     }
 
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        string_value(self.get_bytes(key.as_bytes())?)
+    }
+
+    /// Returns the value of a key, byte for byte.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        value_response(self.send_command(Command::Get(key.to_vec()))?)
+    }
+
     pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
-        match self.send_command(Command::Set(key.to_owned(), value.to_owned()))? {
-            Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::Err(e)),
-        }
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Sets a key to a binary value.
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        unit_response(self.send_command(Command::Set(key.to_vec(), value.to_vec()))?)
+    }
+
+    /// Sets a key that expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    /// Sets a key to a binary value that expires after `ttl`.
+    pub fn set_bytes_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let cmd = Command::SetExpiring(key.to_vec(), value.to_vec(), deadline_after(ttl));
+        unit_response(self.send_command(cmd)?)
     }
 
     pub fn remove(&mut self, key: &str) -> Result<()> {
-        match self.send_command(Command::Rm(key.to_owned()))? {
-            Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::Err(e)),
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Removes a binary key.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        unit_response(self.send_command(Command::Rm(key.to_vec()))?)
+    }
+
+    /// Applies all the writes in `batch` atomically.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        unit_response(self.send_command(Command::Batch(batch))?)
+    }
+
+    /// Sets `key` to `new` if its current value is `expected`, where `None` stands
+    /// for a missing key on both sides.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<&str>,
+    ) -> Result<CasOutcome> {
+        string_outcome(self.compare_and_swap_bytes(
+            key.as_bytes(),
+            expected.map(str::as_bytes),
+            new.map(str::as_bytes),
+        )?)
+    }
+
+    /// Swaps binary values, see `compare_and_swap`.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        let cmd = Command::Cas(
+            key.to_vec(),
+            expected.map(<[u8]>::to_vec),
+            new.map(<[u8]>::to_vec),
+        );
+        cas_response(self.send_command(cmd)?)
+    }
+
+    /// Returns the pairs with keys from `start`, inclusive, to `end`, exclusive.
+    /// A missing bound leaves that side of the range open.
+    pub fn scan(
+        &mut self,
+        start: Option<&str>,
+        end: Option<&str>,
+        options: ScanOptions,
+    ) -> Result<Vec<(String, String)>> {
+        string_pairs(self.scan_bytes(start.map(str::as_bytes), end.map(str::as_bytes), options)?)
+    }
+
+    /// Returns the binary pairs with keys from `start`, inclusive, to `end`, exclusive.
+    pub fn scan_bytes(
+        &mut self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        options: ScanOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = KeyRange::Range(start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec));
+        self.send_scan(range, options)
+    }
+
+    /// Returns the pairs with keys starting with `prefix`.
+    pub fn scan_prefix(
+        &mut self,
+        prefix: &str,
+        options: ScanOptions,
+    ) -> Result<Vec<(String, String)>> {
+        string_pairs(self.scan_prefix_bytes(prefix.as_bytes(), options)?)
+    }
+
+    /// Returns the binary pairs with keys starting with `prefix`.
+    pub fn scan_prefix_bytes(
+        &mut self,
+        prefix: &[u8],
+        options: ScanOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.send_scan(KeyRange::Prefix(prefix.to_vec()), options)
+    }
+
+    fn send_scan(
+        &mut self,
+        range: KeyRange,
+        options: ScanOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        pairs_response(self.send_command(Command::Scan(range, options))?)
+    }
+}
+
+// The responses and conversions below are shared with `AsyncKvsClient`.
+
+/// Fails if `cmd` needs a feature missing from `features`.
+pub(crate) fn require_feature(features: &[String], cmd: &Command) -> Result<()> {
+    match cmd.feature() {
+        Some(feature) if !features.iter().any(|supported| supported == feature) => {
+            Err(KvsError::UnsupportedFeatureError(feature.to_owned()))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn unit_response(res: Response) -> Result<()> {
+    value_response(res).map(|_| ())
+}
+
+pub(crate) fn value_response(res: Response) -> Result<Option<Vec<u8>>> {
+    match res {
+        Response::Ok(value) => Ok(value),
+        Response::Err(code, message) => Err(response_error(code, message)),
+        _ => Err(KvsError::UnexpectedCommandError),
+    }
+}
+
+pub(crate) fn cas_response(res: Response) -> Result<CasOutcome<Vec<u8>>> {
+    match res {
+        Response::Cas(outcome) => Ok(outcome),
+        Response::Err(code, message) => Err(response_error(code, message)),
+        _ => Err(KvsError::UnexpectedCommandError),
+    }
+}
+
+pub(crate) fn pairs_response(res: Response) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    match res {
+        Response::Pairs(pairs) => Ok(pairs),
+        Response::Err(code, message) => Err(response_error(code, message)),
+        _ => Err(KvsError::UnexpectedCommandError),
+    }
+}
+
+pub(crate) fn string_value(value: Option<Vec<u8>>) -> Result<Option<String>> {
+    match value {
+        Some(value) => Ok(Some(String::from_utf8(value)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn string_outcome(outcome: CasOutcome<Vec<u8>>) -> Result<CasOutcome> {
+    Ok(CasOutcome {
+        succeeded: outcome.succeeded,
+        current: string_value(outcome.current)?,
+    })
+}
+
+pub(crate) fn string_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        .collect()
+}
+
+/// Commands queued on a `KvsClient` connection, sent together by `execute`.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    cmds: Vec<Command>,
+}
+
+impl Pipeline<'_> {
+    /// Queues a get of a string key, see `get_bytes`.
+    pub fn get(&mut self, key: &str) {
+        self.get_bytes(key.as_bytes());
+    }
+
+    /// Queues a get. Its result holds the value of the key.
+    pub fn get_bytes(&mut self, key: &[u8]) {
+        self.cmds.push(Command::Get(key.to_vec()));
+    }
+
+    /// Queues a set of a string value, see `set_bytes`.
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.set_bytes(key.as_bytes(), value.as_bytes());
+    }
+
+    /// Queues a set. Its result holds `None`.
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) {
+        self.cmds.push(Command::Set(key.to_vec(), value.to_vec()));
+    }
+
+    /// Queues a removal of a string key, see `remove_bytes`.
+    pub fn remove(&mut self, key: &str) {
+        self.remove_bytes(key.as_bytes());
+    }
+
+    /// Queues a removal. Its result holds `None`, or an error if the key was
+    /// not found.
+    pub fn remove_bytes(&mut self, key: &[u8]) {
+        self.cmds.push(Command::Rm(key.to_vec()));
+    }
+
+    /// Returns the number of queued commands.
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    /// Returns `true` if no commands are queued.
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+
+    /// Sends the queued commands and returns their results, in order. The server
+    /// runs them one after the other, so a failed command does not stop the rest.
+    /// The outer error is for failures of the connection itself.
+    pub fn execute(self) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        let count = self.cmds.len();
+        // Requests are written from another thread while responses are read
+        // here, or both sides could block on full socket buffers.
+        let mut writer = BufWriter::new(self.client.writer.get_ref().try_clone()?);
+        let cmds = self.cmds;
+        let sender = thread::spawn(move || -> Result<()> {
+            for cmd in &cmds {
+                write_frame(&mut writer, cmd)?;
+            }
+            writer.flush()?;
+            Ok(())
+        });
+
+        let mut results = Vec::with_capacity(count);
+        let mut read_error = None;
+        for _ in 0..count {
+            match read_frame(&mut self.client.reader) {
+                Ok(Some(res)) => results.push(value_response(res)),
+                Ok(None) => {
+                    read_error = Some(KvsError::ConnectionClosedError);
+                    break;
+                }
+                Err(e) => {
+                    read_error = Some(e);
+                    break;
+                }
+            }
+        }
+        match sender.join() {
+            Ok(sent) => sent?,
+            Err(_) => return Err(KvsError::Err("Pipeline sender panicked".to_owned())),
+        }
+        match read_error {
+            Some(e) => Err(e),
+            None => Ok(results),
         }
     }
 }
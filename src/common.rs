@@ -1,14 +1,294 @@
+use crate::engines::{CasOutcome, ScanOptions, WriteBatch};
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io::{self, prelude::*};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
-    Set(String, String),
-    Rm(String),
-    Get(String),
+    Set(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+    ),
+    /// Sets a key that expires at the given deadline, in unix milliseconds.
+    SetExpiring(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        u64,
+    ),
+    Rm(#[serde(with = "serde_bytes")] Vec<u8>),
+    Get(#[serde(with = "serde_bytes")] Vec<u8>),
+    Scan(KeyRange, ScanOptions),
+    Batch(WriteBatch),
+    Cas(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+    ),
+}
+
+impl Command {
+    /// Returns the feature the server must support to run this command, if any.
+    pub fn feature(&self) -> Option<&'static str> {
+        match self {
+            Command::Set(..) | Command::Rm(_) | Command::Get(_) => None,
+            Command::SetExpiring(..) => Some("ttl"),
+            Command::Scan(..) => Some("scan"),
+            Command::Batch(_) => Some("batch"),
+            Command::Cas(..) => Some("cas"),
+        }
+    }
+}
+
+/// The keys a scan covers.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum KeyRange {
+    /// Keys from the start, inclusive, to the end, exclusive. A missing bound
+    /// leaves that side of the range open.
+    Range(
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+    ),
+    /// Keys starting with the prefix.
+    Prefix(#[serde(with = "serde_bytes")] Vec<u8>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
-    Ok(Option<String>),
-    Err(String),
+    Ok(#[serde(with = "serde_bytes")] Option<Vec<u8>>),
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    Cas(CasOutcome<Vec<u8>>),
+    Err(ErrorCode, String),
+}
+
+/// The kind of failure a `Response::Err` reports, so that clients can tell them
+/// apart without parsing the message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The key does not exist. The message is the key.
+    NotFound,
+    /// The command cannot be run as sent, e.g. it is too large for the log.
+    InvalidRequest,
+    /// The engine failed, e.g. on a corrupted log.
+    Engine,
+    /// The server failed to read or write its files.
+    Io,
+}
+
+impl Response {
+    /// Reports `error` to the client.
+    pub fn error(error: KvsError) -> Self {
+        match error {
+            KvsError::NotFoundError(key) => Response::Err(ErrorCode::NotFound, key),
+            KvsError::IOError(e) => Response::Err(ErrorCode::Io, e.to_string()),
+            KvsError::InvalidRequestError(message) => {
+                Response::Err(ErrorCode::InvalidRequest, message)
+            }
+            KvsError::StringParseError(_) | KvsError::UnexpectedCommandError => {
+                Response::Err(ErrorCode::InvalidRequest, error.to_string())
+            }
+            KvsError::EngineError(message) | KvsError::Err(message) => {
+                Response::Err(ErrorCode::Engine, message)
+            }
+            e => Response::Err(ErrorCode::Engine, e.to_string()),
+        }
+    }
+}
+
+/// Turns the error a server reported back into the `KvsError` it stands for.
+pub fn response_error(code: ErrorCode, message: String) -> KvsError {
+    match code {
+        ErrorCode::NotFound => KvsError::NotFoundError(message),
+        ErrorCode::InvalidRequest => KvsError::InvalidRequestError(message),
+        ErrorCode::Engine => KvsError::EngineError(message),
+        ErrorCode::Io => KvsError::IOError(io::Error::other(message)),
+    }
+}
+
+/// The version of the protocol this build speaks, and the oldest one it still
+/// speaks. Both sides use the highest version they have in common.
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// The optional commands this build supports. A client only sends those the
+/// server supports too.
+pub const FEATURES: [&str; 4] = ["batch", "cas", "scan", "ttl"];
+
+/// Starts every `Hello`, so that the server can tell clients that do not
+/// handshake from garbled hellos.
+const HELLO_MAGIC: u32 = 0x4b56_5348;
+
+/// The first message a client sends on a connection. Its layout must never
+/// change, so that any two versions can at least tell they are incompatible.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    magic: u32,
+    version: u16,
+    features: Vec<String>,
+}
+
+/// The server's answer to a `Hello`. Its variants must never change, and new
+/// ones only go at the end, so that older clients can decode those they know.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HelloReply {
+    /// The version spoken on the connection, and the features both sides support.
+    Accepted { version: u16, features: Vec<String> },
+    /// Why the client cannot be served. The server then closes the connection.
+    Rejected(String),
+    /// The server has too many clients to serve this one, and closes the
+    /// connection. It may be sent before the server reads the `Hello`.
+    Busy,
+}
+
+impl Hello {
+    /// Returns the hello of this build.
+    pub fn current() -> Self {
+        Hello {
+            magic: HELLO_MAGIC,
+            version: PROTOCOL_VERSION,
+            features: FEATURES
+                .iter()
+                .map(|feature| (*feature).to_owned())
+                .collect(),
+        }
+    }
+
+    fn answer(self) -> HelloReply {
+        if self.magic != HELLO_MAGIC {
+            return not_hello();
+        }
+        if self.version < MIN_PROTOCOL_VERSION {
+            return HelloReply::Rejected(format!(
+                "Client protocol version {} is too old, the server speaks {} to {}",
+                self.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ));
+        }
+        HelloReply::Accepted {
+            version: self.version.min(PROTOCOL_VERSION),
+            features: self
+                .features
+                .into_iter()
+                .filter(|feature| FEATURES.contains(&&feature[..]))
+                .collect(),
+        }
+    }
+}
+
+impl HelloReply {
+    /// Returns the features of the connection, or an error if either side
+    /// cannot speak the version the other offered.
+    pub fn features(self) -> Result<Vec<String>> {
+        match self {
+            HelloReply::Accepted { version, .. } if version < MIN_PROTOCOL_VERSION => {
+                Err(KvsError::IncompatibleProtocolError(format!(
+                    "Server protocol version {} is too old, the client speaks {} to {}",
+                    version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                )))
+            }
+            HelloReply::Accepted { features, .. } => Ok(features),
+            HelloReply::Rejected(reason) => Err(KvsError::IncompatibleProtocolError(reason)),
+            HelloReply::Busy => Err(KvsError::ServerBusyError),
+        }
+    }
+}
+
+/// Answers the first frame of a connection, as read by the server. Returns
+/// `None` if the client closed the connection instead of sending one.
+pub fn answer_hello(hello: Result<Option<Hello>>) -> Result<Option<HelloReply>> {
+    match hello {
+        Ok(Some(hello)) => Ok(Some(hello.answer())),
+        Ok(None) => Ok(None),
+        Err(KvsError::ProtocolError(_)) | Err(KvsError::FrameTooLargeError(_)) => {
+            Ok(Some(not_hello()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn not_hello() -> HelloReply {
+    HelloReply::Rejected("Expected a hello, the client may be too old".to_owned())
+}
+
+/// The largest frame either side accepts, so that a corrupt length prefix cannot
+/// make the reader allocate without bound.
+const MAX_FRAME_LEN: u32 = 256 * 1024 * 1024;
+
+/// Writes `message` as one frame: its length as a big endian `u32`, followed by
+/// its bincode encoding. The writer is not flushed.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let len = bincode::serialized_size(message)?;
+    if len > u64::from(MAX_FRAME_LEN) {
+        return Err(KvsError::FrameTooLargeError(len));
+    }
+    writer.write_all(&(len as u32).to_be_bytes())?;
+    bincode::serialize_into(writer, message)?;
+    Ok(())
+}
+
+/// Reads a frame written by `write_frame`. Returns `None` if the stream ends
+/// cleanly before the frame starts.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(KvsError::ConnectionClosedError),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::FrameTooLargeError(len.into()));
+    }
+    // The buffer only grows as the frame arrives, so a peer cannot make it
+    // allocate a whole frame with the length alone.
+    let mut frame = Vec::new();
+    reader.take(len.into()).read_to_end(&mut frame)?;
+    if frame.len() < len as usize {
+        return Err(KvsError::ConnectionClosedError);
+    }
+    Ok(Some(bincode::deserialize(&frame)?))
+}
+
+/// Writes a frame like `write_frame`, to an async writer.
+pub async fn write_frame_async<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut frame = Vec::new();
+    write_frame(&mut frame, message)?;
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Reads a frame like `read_frame`, from an async reader.
+pub async fn read_frame_async<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..]).await? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(KvsError::ConnectionClosedError),
+            n => read += n,
+        }
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::FrameTooLargeError(len.into()));
+    }
+    let mut frame = Vec::new();
+    reader.take(len.into()).read_to_end(&mut frame).await?;
+    if frame.len() < len as usize {
+        return Err(KvsError::ConnectionClosedError);
+    }
+    Ok(Some(bincode::deserialize(&frame)?))
 }
@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of writes that `KvsEngine::write_batch` applies atomically: readers
+/// and crash recovery either see all of them or none.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+    ),
+    Remove(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+impl WriteBatch {
+    /// Returns an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a string value when the batch is written, see `set_bytes`.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// Sets `key` to `value` when the batch is written.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set(key, value));
+    }
+
+    /// Removes a string key when the batch is written, see `remove_bytes`.
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Removes `key` when the batch is written. Unlike `KvsEngine::remove_bytes`,
+    /// removing a key that does not exist is not an error.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove(key));
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -1,93 +1,117 @@
-use super::KvsEngine;
+use super::batch::BatchOp;
+use super::{
+    deadline_after, unix_millis, CasOutcome, KvStoreOptions, KvsEngine, ScanOptions, SyncPolicy,
+    WriteBatch,
+};
 use crate::errors::{KvsError, Result};
-use serde::{Deserialize, Serialize};
+use crc32fast::Hasher;
+use crossbeam_skiplist::SkipMap;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
 use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap};
 use std::io::prelude::*;
+use std::mem;
+use std::ops::RangeBounds;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec;
 use std::{ffi::OsStr, fs, io, path};
+
+/// The in-memory index. Overwritten keys are updated in place rather than
+/// re-inserted, since replacing a skip list node briefly hides the key from
+/// concurrent lookups.
+type Index = SkipMap<Vec<u8>, RwLock<CommandPos>>;
+
 /// KvStore serves as the storage data structure for
 /// our database.
 #[derive(Clone)]
 pub struct KvStore {
-    mem_map: Arc<Mutex<BTreeMap<String, CommandPos>>>,
+    // Lookups never wait on writers: the index is only mutated under the writer
+    // lock, and values are read from disk after the entry has been copied out.
+    // The one exception is a batch, whose entries only appear together.
+    mem_map: Arc<Index>,
+    // Odd while a batch is applied to the index. Readers retry whenever it
+    // changed while they copied entries out of the index.
+    batch_epoch: Arc<AtomicU64>,
     path: Arc<path::PathBuf>,
     reader: KvReader,
+    // Must be dropped before `_background`, which waits for the background
+    // thread to exit once the writer is gone.
     writer: Arc<Mutex<KvWriter>>,
+    commit_queue: Arc<CommitQueue>,
+    _background: Arc<BackgroundWorker>,
 }
 
 struct KvReader {
-    mem_map: Arc<Mutex<BTreeMap<String, CommandPos>>>,
     path: Arc<path::PathBuf>,
-    readers: RefCell<HashMap<u64, io::BufReader<fs::File>>>,
+    readers: RefCell<HashMap<u64, LogReader>>,
     safe_point: Arc<AtomicU64>,
 }
 
 impl KvReader {
     fn read(&self, cmd_pos: &CommandPos) -> Result<Command> {
-        self.remove_stale_files()?;
-        let mut readers = self.readers.borrow_mut();
-        if !readers.contains_key(&cmd_pos.file_no) {
-            readers.insert(
-                cmd_pos.file_no,
-                io::BufReader::new(fs::File::open(log_path(&self.path, cmd_pos.file_no))?),
-            );
-        }
-
-        let reader = readers.get_mut(&cmd_pos.file_no).unwrap();
-        reader.seek(io::SeekFrom::Start(cmd_pos.start))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        Ok(serde_json::from_reader(cmd_reader)?)
+        let (version, record) = self.read_raw(cmd_pos)?;
+        decode_record(version, &record)
+            .ok_or(KvsError::CorruptedLogError(cmd_pos.file_no, cmd_pos.start))
     }
 
+    /// Copies the record at `cmd_pos` to `writer` and returns the number of bytes written.
+    /// Records from older log formats are re-encoded in the current one.
     fn read_and_copy(
         &self,
-        cmd_pos: &mut CommandPos,
-        mut writer: &mut io::BufWriter<fs::File>,
+        cmd_pos: &CommandPos,
+        writer: &mut io::BufWriter<fs::File>,
     ) -> Result<u64> {
-        self.remove_stale_files()?;
+        let (version, record) = self.read_raw(cmd_pos)?;
+        if version == LOG_VERSION {
+            writer.write_all(&record)?;
+            return Ok(record.len() as u64);
+        }
+        let cmd = decode_record(version, &record)
+            .ok_or(KvsError::CorruptedLogError(cmd_pos.file_no, cmd_pos.start))?;
+        let record = encode_record(&cmd)?;
+        writer.write_all(&record)?;
+        Ok(record.len() as u64)
+    }
+
+    fn read_raw(&self, cmd_pos: &CommandPos) -> Result<(u8, Vec<u8>)> {
+        self.close_stale_readers();
         let mut readers = self.readers.borrow_mut();
         if !readers.contains_key(&cmd_pos.file_no) {
             readers.insert(
                 cmd_pos.file_no,
-                io::BufReader::new(fs::File::open(log_path(&self.path, cmd_pos.file_no))?),
+                LogReader::open(&log_path(&self.path, cmd_pos.file_no))?,
             );
         }
-        let reader = readers
+        let log_reader = readers
             .get_mut(&cmd_pos.file_no)
             .expect("Couldn't find log reader");
-        reader.seek(io::SeekFrom::Start(cmd_pos.start))?;
-
-        let mut cmd_reader = reader.take(cmd_pos.len);
-        let len = io::copy(&mut cmd_reader, &mut writer)?;
-        Ok(len)
+        log_reader.reader.seek(io::SeekFrom::Start(cmd_pos.start))?;
+        let mut record = vec![0; cmd_pos.len as usize];
+        log_reader.reader.read_exact(&mut record)?;
+        Ok((log_reader.version, record))
     }
 
     fn update_safe_point(&self, safe_point: u64) {
         self.safe_point.store(safe_point, Ordering::SeqCst);
     }
 
-    fn remove_stale_files(&self) -> Result<()> {
-        let stale_files: Vec<_> = self
-            .readers
-            .borrow()
-            .keys()
-            .filter(|&&file_no| file_no < self.safe_point.load(Ordering::SeqCst))
-            .cloned()
-            .collect();
-        for stale_file in stale_files {
-            self.readers.borrow_mut().remove(&stale_file);
-            fs::remove_file(log_path(&self.path, stale_file))?;
-        }
-        Ok(())
+    /// Closes the handles of log files that compaction has made obsolete.
+    /// The files themselves are removed by the compaction thread.
+    fn close_stale_readers(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        self.readers
+            .borrow_mut()
+            .retain(|&file_no, _| file_no >= safe_point);
     }
 }
 
 impl Clone for KvReader {
     fn clone(&self) -> Self {
         KvReader {
-            mem_map: self.mem_map.clone(),
             path: self.path.clone(),
             readers: RefCell::new(HashMap::new()),
             safe_point: self.safe_point.clone(),
@@ -100,152 +124,696 @@ pub struct KvWriter {
     writer: io::BufWriter<fs::File>,
     current_file_no: u64,
     uncompacted_bytes: u64,
+    total_bytes: u64,
     path: Arc<path::PathBuf>,
-    mem_map: Arc<Mutex<BTreeMap<String, CommandPos>>>,
+    mem_map: Arc<Index>,
+    batch_epoch: Arc<AtomicU64>,
+    options: KvStoreOptions,
+    last_sync: Instant,
+    unsynced: bool,
+    compaction_tx: mpsc::Sender<()>,
+    compacting: bool,
 }
 
 impl KvWriter {
-    fn write(&mut self, cmd: &Command) -> Result<()> {
-        let pos = self.writer.seek(io::SeekFrom::Current(0))?;
-        serde_json::to_writer(&mut self.writer, cmd)?;
-        self.writer.flush()?;
-        let new_pos = self.writer.seek(io::SeekFrom::Current(0))?;
-        let cmd_pos = (self.current_file_no, pos, new_pos).into();
-        match cmd {
-            Command::Set(key, _) => {
-                if let Some(old_cmd) = self.mem_map.lock().unwrap().insert(key.to_owned(), cmd_pos)
-                {
-                    self.uncompacted_bytes += old_cmd.len;
+    /// Appends a group of queued commands to the log with a single write and sync,
+    /// and applies them to the index in order. Returns the outcome of every command:
+    /// removing a missing key fails on its own, while an IO error fails them all.
+    fn write_group(&mut self, cmds: Vec<Command>) -> Vec<Result<()>> {
+        let mut results = Vec::with_capacity(cmds.len());
+        let mut accepted = Vec::with_capacity(cmds.len());
+        let mut applied = Vec::with_capacity(cmds.len());
+        let mut records = Vec::new();
+        let mut has_batch = false;
+        {
+            // Later commands in the group see the keys set or removed by earlier ones.
+            let mem_map = &self.mem_map;
+            let mut group_keys = HashMap::new();
+            for cmd in cmds {
+                if let Command::Rm(key) = &cmd {
+                    let present = group_keys.get(key).cloned().unwrap_or_else(|| {
+                        mem_map.get(key).is_some_and(|entry| {
+                            !entry.value().read().unwrap().is_expired(unix_millis())
+                        })
+                    });
+                    if !present {
+                        let key = String::from_utf8_lossy(key).into_owned();
+                        results.push(Err(KvsError::NotFoundError(key)));
+                        continue;
+                    }
+                }
+                let encoded = match cmd {
+                    Command::Batch(cmds) => {
+                        has_batch = true;
+                        encode_batch(cmds)
+                    }
+                    cmd => encode_record(&cmd).map(|record| {
+                        let len = record.len() as u64;
+                        (record, vec![(cmd, 0, len)])
+                    }),
+                };
+                match encoded {
+                    Ok((record, cmds)) => {
+                        let start = records.len() as u64;
+                        for (cmd, cmd_start, cmd_end) in cmds {
+                            if let Command::Set(key, _)
+                            | Command::SetExpiring(key, _, _)
+                            | Command::Rm(key) = &cmd
+                            {
+                                let present = !matches!(cmd, Command::Rm(_));
+                                group_keys.insert(key.to_owned(), present);
+                            }
+                            applied.push((cmd, start + cmd_start, start + cmd_end));
+                        }
+                        records.extend_from_slice(&record);
+                        accepted.push(results.len());
+                        results.push(Ok(()));
+                    }
+                    Err(e) => results.push(Err(e)),
                 }
             }
-            Command::Rm(key) => {
-                let old_cmd = self
-                    .mem_map
-                    .lock()
-                    .unwrap()
-                    .remove(&key.to_owned())
-                    .expect("Key not found!");
-                self.uncompacted_bytes += old_cmd.len;
+        }
+        if accepted.is_empty() {
+            return results;
+        }
+
+        let pos = match self.append(&records) {
+            Ok(pos) => pos,
+            Err(e) => {
+                for i in accepted {
+                    results[i] = Err(group_error(&e));
+                }
+                return results;
+            }
+        };
+        // The headers of batch records never hold a live value.
+        let mut framing = records.len() as u64;
+        if has_batch {
+            self.batch_epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        for (cmd, start, end) in applied {
+            framing -= end - start;
+            let mut cmd_pos = CommandPos::from((self.current_file_no, pos + start, pos + end));
+            cmd_pos.expires_at = cmd.expires_at();
+            match cmd {
+                Command::Set(key, _) | Command::SetExpiring(key, _, _) => {
+                    match self.mem_map.get(&key) {
+                        Some(entry) => {
+                            let mut old_cmd = entry.value().write().unwrap();
+                            self.uncompacted_bytes += old_cmd.len;
+                            *old_cmd = cmd_pos;
+                        }
+                        None => {
+                            self.mem_map.insert(key, RwLock::new(cmd_pos));
+                        }
+                    }
+                }
+                Command::Rm(key) => {
+                    if let Some(old_cmd) = self.mem_map.remove(&key) {
+                        self.uncompacted_bytes += old_cmd.value().read().unwrap().len;
+                    }
+                    self.uncompacted_bytes += cmd_pos.len;
+                }
+                Command::Batch(_) => {}
+            }
+        }
+        if has_batch {
+            self.batch_epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        self.uncompacted_bytes += framing;
+
+        // The group is already in the log at this point. A failed rollover
+        // leaves the active file oversized and is retried after the next append.
+        let _ = self.after_append();
+        results
+    }
+
+    /// Reads the current value of `key`. Compaction cannot move it away in the
+    /// meantime, since the index is only updated under the writer lock.
+    fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let cmd_pos = match self.mem_map.get(key) {
+            Some(entry) => entry.value().read().unwrap().clone(),
+            None => return Ok(None),
+        };
+        if cmd_pos.is_expired(unix_millis()) {
+            return Ok(None);
+        }
+        match self.reader.read(&cmd_pos)? {
+            Command::Set(_, value) | Command::SetExpiring(_, value, _) => Ok(Some(value)),
+            _ => Err(KvsError::UnexpectedCommandError),
+        }
+    }
+
+    /// Writes encoded records to the active log and syncs them according to
+    /// the sync policy. Returns the position they were written at.
+    fn append(&mut self, records: &[u8]) -> Result<u64> {
+        let pos = self.writer.stream_position()?;
+        if let Err(e) = self
+            .writer
+            .write_all(records)
+            .and_then(|_| self.writer.flush())
+        {
+            // Cut off whatever part of the batch made it to the file, so the
+            // next append does not land behind a torn record.
+            self.truncate_active_log(pos)?;
+            return Err(e.into());
+        }
+        self.unsynced = true;
+        let synced = match self.options.sync_policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        };
+        if let Err(e) = synced {
+            // The commands are reported as failed, so they must not come back
+            // when the log is replayed.
+            self.truncate_active_log(pos)?;
+            return Err(e);
+        }
+        self.total_bytes += records.len() as u64;
+        Ok(pos)
+    }
+
+    /// Rolls the active log over if it grew too large, and asks for a
+    /// compaction if enough of the log has gone stale.
+    fn after_append(&mut self) -> Result<()> {
+        if let Some(max_file_size) = self.options.max_file_size {
+            if self.writer.stream_position()? >= max_file_size {
+                let next_file_no = self.current_file_no + 1;
+                self.roll(next_file_no)?;
             }
-            _ => {}
         }
         // Initiate compaction ?
-        if self.uncompacted_bytes > COMPACTION_THRESHOLD {
-            self.compaction()?;
+        if self.needs_compaction() && !self.compacting {
+            self.compacting = true;
+            // The background thread only goes away together with the writer.
+            let _ = self.compaction_tx.send(());
         }
         Ok(())
     }
 
-    fn compaction(&mut self) -> Result<()> {
-        let compaction_no = self.current_file_no + 1;
-        self.current_file_no += 2;
-        self.writer = new_db_file(&self.path, self.current_file_no, &self.reader)?;
-        let mut compaction_writer = new_db_file(&self.path, compaction_no, &self.reader)?;
-
-        let mut pos = 0;
-        for cmd_pos in self.mem_map.lock().unwrap().values_mut() {
-            let len = self.reader.read_and_copy(cmd_pos, &mut compaction_writer)?;
-            *cmd_pos = (compaction_no, pos, pos + len).into();
-            pos += len;
-        }
-        compaction_writer.flush()?;
-        self.reader.update_safe_point(compaction_no);
-        self.reader.remove_stale_files()?;
-        self.uncompacted_bytes = 0;
+    fn truncate_active_log(&mut self, len: u64) -> Result<()> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(log_path(&self.path, self.current_file_no))?;
+        file.set_len(len)?;
+        // Drop the failed writer without flushing what is left in its buffer.
+        let failed_writer = mem::replace(&mut self.writer, io::BufWriter::new(file));
+        let _ = failed_writer.into_parts();
         Ok(())
     }
-}
 
-const COMPACTION_THRESHOLD: u64 = 1024;
+    fn needs_compaction(&self) -> bool {
+        self.uncompacted_bytes > self.options.compaction_threshold
+            && self.options.compaction_ratio.is_none_or(|ratio| {
+                self.uncompacted_bytes as f64 >= ratio * self.total_bytes as f64
+            })
+    }
 
-impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set(key, value);
-        self.writer.lock().unwrap().write(&cmd)?;
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = false;
+        self.last_sync = Instant::now();
         Ok(())
     }
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.mem_map.lock().unwrap().get(&key) {
-            if let Command::Set(_, value) = self.reader.read(cmd_pos)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandError)
+
+    /// Closes the active log file and continues writing to a new one.
+    fn roll(&mut self, file_no: u64) -> Result<()> {
+        if self.options.sync_policy != SyncPolicy::Never && self.unsynced {
+            self.sync()?;
+        }
+        self.writer = new_db_file(&self.path, file_no, &self.reader)?;
+        self.current_file_no = file_no;
+        self.total_bytes += LOG_HEADER_LEN;
+        Ok(())
+    }
+
+    /// Switches writes over to a fresh log file and reserves the file number in
+    /// between for the compacted log. Returns that number, the size of the logs
+    /// about to be compacted and how much of them is stale.
+    fn start_compaction(&mut self) -> Result<(u64, u64, u64)> {
+        let compaction_no = self.current_file_no + 1;
+        let compacted_bytes = self.total_bytes;
+        self.roll(compaction_no + 1)?;
+        Ok((compaction_no, compacted_bytes, self.uncompacted_bytes))
+    }
+}
+
+impl Drop for KvWriter {
+    fn drop(&mut self) {
+        if self.unsynced && self.options.sync_policy != SyncPolicy::Never {
+            let _ = self.sync();
+        }
+    }
+}
+
+/// Copies an error that has to be reported to every command of a failed group.
+fn group_error(error: &KvsError) -> KvsError {
+    match error {
+        KvsError::IOError(e) => KvsError::IOError(io::Error::new(e.kind(), e.to_string())),
+        e => KvsError::Err(e.to_string()),
+    }
+}
+
+/// Group commit for concurrent writers. Every writer queues its command, and
+/// whichever one finds no commit in progress becomes the leader: it writes and
+/// syncs everything queued so far as one batch, publishes the outcome of each
+/// command and wakes the others up. Writers arriving in the meantime queue up
+/// for the next batch, so a single sync covers many writes under load.
+#[derive(Default)]
+struct CommitQueue {
+    state: Mutex<CommitState>,
+    committed: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    pending: Vec<(u64, Command)>,
+    results: HashMap<u64, Result<()>>,
+    next_ticket: u64,
+    committing: bool,
+}
+
+impl CommitQueue {
+    fn commit(&self, writer: &Mutex<KvWriter>, cmd: Command) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, cmd));
+        loop {
+            if let Some(result) = state.results.remove(&ticket) {
+                return result;
             }
-        } else {
-            Ok(None)
+            if state.committing {
+                state = self.committed.wait(state).unwrap();
+                continue;
+            }
+
+            state.committing = true;
+            let (tickets, cmds): (Vec<_>, Vec<_>) =
+                mem::take(&mut state.pending).into_iter().unzip();
+            drop(state);
+            let results = writer.lock().unwrap().write_group(cmds);
+            state = self.state.lock().unwrap();
+            state.results.extend(tickets.into_iter().zip(results));
+            state.committing = false;
+            self.committed.notify_all();
         }
     }
+}
 
-    fn remove(&self, key: String) -> Result<()> {
-        let mem_map = self.mem_map.lock().unwrap();
-        if mem_map.contains_key(&key) {
-            let cmd = Command::Rm(key);
-            self.writer.lock().unwrap().write(&cmd)?;
-            Ok(())
-        } else {
-            Err(KvsError::NotFoundError(key))
+/// Owns the background thread that compacts the log and runs interval syncs,
+/// and stops it once the last handle to the store is dropped.
+struct BackgroundWorker {
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for BackgroundWorker {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
         }
     }
 }
 
+/// Runs a compaction every time the writer asks for one, and syncs the active
+/// log every `sync_interval` if one is given, until the writer is dropped.
+fn background_loop(
+    writer: Weak<Mutex<KvWriter>>,
+    compaction_rx: mpsc::Receiver<()>,
+    sync_interval: Option<Duration>,
+) {
+    loop {
+        let request = match sync_interval {
+            Some(interval) => compaction_rx.recv_timeout(interval),
+            None => compaction_rx
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => break,
+        };
+        match request {
+            Ok(()) => {
+                // A failed compaction leaves the old logs and the stale byte count
+                // in place, so it is simply retried on the next write.
+                if compaction(&writer).is_err() {
+                    writer.lock().unwrap().compacting = false;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let mut writer = writer.lock().unwrap();
+                if writer.unsynced {
+                    // Failures resurface on the next write, which syncs again.
+                    let _ = writer.sync();
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Rewrites the live records of every log older than the active one into a single
+/// compacted log. Writers keep appending to a new active log in the meantime and
+/// are only blocked while the moved index entries are swapped in.
+fn compaction(writer: &Mutex<KvWriter>) -> Result<()> {
+    let (compaction_no, compacted_bytes, reclaimed_bytes, path, reader, mem_map) = {
+        let mut writer = writer.lock().unwrap();
+        let (compaction_no, compacted_bytes, reclaimed_bytes) = writer.start_compaction()?;
+        (
+            compaction_no,
+            compacted_bytes,
+            reclaimed_bytes,
+            writer.path.clone(),
+            writer.reader.clone(),
+            writer.mem_map.clone(),
+        )
+    };
+
+    // The compacted log is written under a temporary name and only renamed
+    // once it is complete, so a crash mid-compaction never leaves a torn
+    // `.db` file behind.
+    let temp_path = compaction_path(&path, compaction_no);
+    let mut compaction_writer = new_log_file(&temp_path)?;
+    // Expired keys are not copied over, and dropped from the index instead.
+    let now = unix_millis();
+    let (expired, live_cmds): (Vec<_>, Vec<_>) = mem_map
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().read().unwrap().clone()))
+        .filter(|(_, cmd_pos)| cmd_pos.file_no < compaction_no)
+        .partition(|(_, cmd_pos)| cmd_pos.is_expired(now));
+    let mut moved = Vec::with_capacity(live_cmds.len());
+    let mut pos = LOG_HEADER_LEN;
+    for (key, cmd_pos) in live_cmds {
+        let len = reader.read_and_copy(&cmd_pos, &mut compaction_writer)?;
+        let mut new_pos = CommandPos::from((compaction_no, pos, pos + len));
+        new_pos.expires_at = cmd_pos.expires_at;
+        moved.push((key, cmd_pos, new_pos));
+        pos += len;
+    }
+    compaction_writer.flush()?;
+    compaction_writer.get_ref().sync_all()?;
+    fs::rename(&temp_path, log_path(&path, compaction_no))?;
+    let hints: Vec<_> = moved
+        .iter()
+        .map(|(key, _, new_pos)| (key.clone(), new_pos.clone()))
+        .collect();
+    write_hint_file(&path, compaction_no, pos, &hints)?;
+
+    // Entries written while the compaction was running point at newer logs
+    // and must be left alone.
+    let mut writer = writer.lock().unwrap();
+    for (key, old_pos, new_pos) in moved {
+        if let Some(entry) = mem_map.get(&key) {
+            let mut cmd_pos = entry.value().write().unwrap();
+            if *cmd_pos == old_pos {
+                *cmd_pos = new_pos;
+            }
+        }
+    }
+    for (key, old_pos) in expired {
+        let unchanged = mem_map
+            .get(&key)
+            .is_some_and(|entry| *entry.value().read().unwrap() == old_pos);
+        if unchanged {
+            mem_map.remove(&key);
+        }
+    }
+    reader.update_safe_point(compaction_no);
+    writer.total_bytes = writer.total_bytes.saturating_sub(compacted_bytes) + pos;
+    // Stale bytes counted while the compaction was running are left for the next one.
+    writer.uncompacted_bytes = writer.uncompacted_bytes.saturating_sub(reclaimed_bytes);
+    writer.compacting = false;
+    drop(writer);
+    remove_stale_logs(&path, compaction_no)
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.commit_queue
+            .commit(&self.writer, Command::Set(key, value))
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let cmd = Command::SetExpiring(key, value, deadline_after(ttl));
+        self.commit_queue.commit(&self.writer, cmd)
+    }
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.lookup(&key) {
+            Some(cmd_pos) => self.read_value(&key, cmd_pos),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        // The writer checks that the key exists right before removing it.
+        self.commit_queue.commit(&self.writer, Command::Rm(key))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let cmds = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => Command::Set(key, value),
+                BatchOp::Remove(key) => Command::Rm(key),
+            })
+            .collect();
+        self.commit_queue.commit(&self.writer, Command::Batch(cmds))
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        // Holding the writer lock keeps every other write out between the
+        // comparison and the swap.
+        let mut writer = self.writer.lock().unwrap();
+        let current = writer.read_value(&key)?;
+        if current != expected {
+            return Ok(CasOutcome {
+                succeeded: false,
+                current,
+            });
+        }
+        let cmd = match (&new, current) {
+            (Some(value), _) => Command::Set(key, value.to_owned()),
+            (None, Some(_)) => Command::Rm(key),
+            (None, None) => {
+                return Ok(CasOutcome {
+                    succeeded: true,
+                    current: None,
+                })
+            }
+        };
+        writer
+            .write_group(vec![cmd])
+            .pop()
+            .expect("Missing result of written command")?;
+        Ok(CasOutcome {
+            succeeded: true,
+            current: new,
+        })
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<vec::IntoIter<(Vec<u8>, Vec<u8>)>> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let now = unix_millis();
+        let positions: Vec<_> = self.read_index(|| {
+            let entries = self.mem_map.range(bounds.clone());
+            let entries: Box<dyn Iterator<Item = _>> = if options.reverse {
+                Box::new(entries.rev())
+            } else {
+                Box::new(entries)
+            };
+            entries
+                .map(|entry| (entry.key().clone(), entry.value().read().unwrap().clone()))
+                .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+                .take(options.limit.unwrap_or(usize::MAX))
+                .collect()
+        });
+        let mut pairs = Vec::with_capacity(positions.len());
+        for (key, cmd_pos) in positions {
+            // Keys removed since the range was read are skipped.
+            if let Some(value) = self.read_value(&key, cmd_pos)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs.into_iter())
+    }
+}
+
 impl KvStore {
+    /// Returns the position of the value of `key`, unless the key is missing or expired.
+    fn lookup(&self, key: &[u8]) -> Option<CommandPos> {
+        self.read_index(|| {
+            self.mem_map
+                .get(key)
+                .map(|entry| entry.value().read().unwrap().clone())
+                .filter(|cmd_pos| !cmd_pos.is_expired(unix_millis()))
+        })
+    }
+
+    /// Runs `read` on the index until no batch was applied while it ran, so
+    /// that a batch is either seen as a whole or not at all.
+    fn read_index<T>(&self, read: impl Fn() -> T) -> T {
+        loop {
+            let epoch = self.batch_epoch.load(Ordering::SeqCst);
+            if epoch.is_multiple_of(2) {
+                let found = read();
+                if self.batch_epoch.load(Ordering::SeqCst) == epoch {
+                    return found;
+                }
+            }
+            thread::yield_now();
+        }
+    }
+
+    /// Reads the value `key` was found at. Returns `None` if the key has been
+    /// removed in the meantime.
+    fn read_value(&self, key: &[u8], mut cmd_pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        loop {
+            match self.reader.read(&cmd_pos) {
+                Ok(Command::Set(_, value)) | Ok(Command::SetExpiring(_, value, _)) => {
+                    return Ok(Some(value))
+                }
+                Ok(_) => return Err(KvsError::UnexpectedCommandError),
+                // A compaction removed the log between the lookup and the read,
+                // after moving the entry somewhere else.
+                Err(KvsError::IOError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    match self.lookup(key) {
+                        Some(new_pos) if new_pos != cmd_pos => cmd_pos = new_pos,
+                        Some(_) => return Err(e.into()),
+                        None => return Ok(None),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// Open specific file from bitcask
     pub fn open(path: &path::Path) -> Result<Self> {
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens the store at `path` with the given options.
+    pub fn open_with(path: &path::Path, options: KvStoreOptions) -> Result<Self> {
+        options.validate()?;
         let path = path.to_path_buf();
         fs::create_dir_all(&path)?;
+        remove_compaction_leftovers(&path)?;
         let mut readers = HashMap::new();
         let mut mem_map = BTreeMap::new();
         let file_list = get_sorted_file_list(&path)?;
         let mut uncompacted_bytes = 0;
+        let mut total_bytes = LOG_HEADER_LEN;
 
         for &file_no in &file_list {
-            let mut reader = io::BufReader::new(fs::File::open(log_path(&path, file_no))?);
-            uncompacted_bytes += intialise_mem_map(file_no, &mut reader, &mut mem_map)?;
-            readers.insert(file_no, reader);
+            let is_newest = Some(&file_no) == file_list.last();
+            uncompacted_bytes += match load_hint_file(&path, file_no, &mut mem_map)? {
+                Some(uncompacted) => uncompacted,
+                None => intialise_mem_map(&path, file_no, is_newest, &mut mem_map)?,
+            };
+            let log_reader = LogReader::open(&log_path(&path, file_no))?;
+            total_bytes += log_reader.reader.get_ref().metadata()?.len();
+            readers.insert(file_no, log_reader);
         }
 
         let current_file_no = file_list.last().unwrap_or(&0) + 1;
         let path = Arc::new(path);
-        let mem_map = Arc::new(Mutex::new(mem_map));
+        let mem_map: Arc<Index> = Arc::new(
+            mem_map
+                .into_iter()
+                .map(|(key, cmd_pos)| (key, RwLock::new(cmd_pos)))
+                .collect(),
+        );
+        let batch_epoch = Arc::new(AtomicU64::new(0));
         let readers = RefCell::new(readers);
         let mut kv_reader = KvReader {
-            readers: readers,
-            mem_map: mem_map.clone(),
+            readers,
             path: path.clone(),
             safe_point: Arc::new(AtomicU64::new(0)),
         };
         let buf_writer = new_db_file(&path, current_file_no, &mut kv_reader)?;
+        let (compaction_tx, compaction_rx) = mpsc::channel();
+        let sync_interval = match options.sync_policy {
+            SyncPolicy::Interval(interval) => Some(interval),
+            _ => None,
+        };
         let kv_writer = KvWriter {
             reader: kv_reader.clone(),
             writer: buf_writer,
             uncompacted_bytes,
+            total_bytes,
             current_file_no,
             path: path.clone(),
             mem_map: mem_map.clone(),
+            batch_epoch: batch_epoch.clone(),
+            options,
+            last_sync: Instant::now(),
+            unsynced: false,
+            compaction_tx,
+            compacting: false,
         };
+        let writer = Arc::new(Mutex::new(kv_writer));
+        let weak_writer = Arc::downgrade(&writer);
+        let handle = thread::Builder::new()
+            .name("kvs-background".into())
+            .spawn(move || background_loop(weak_writer, compaction_rx, sync_interval))?;
         let kv_store = KvStore {
             mem_map,
+            batch_epoch,
             path,
             reader: kv_reader,
-            writer: Arc::new(Mutex::new(kv_writer)),
+            writer,
+            commit_queue: Arc::new(CommitQueue::default()),
+            _background: Arc::new(BackgroundWorker {
+                handle: Some(handle),
+            }),
         };
         Ok(kv_store)
     }
 }
 
+/// Replays a log file into `mem_map` and returns the number of stale bytes in it.
+///
+/// A torn or corrupted record at the end of the newest file is the result of a
+/// crash in the middle of a write, so the file is truncated right before it. The
+/// same damage followed by valid records, or in any older file, is reported as a
+/// `CorruptedLogError`.
 fn intialise_mem_map(
+    path: &path::Path,
     file_no: u64,
-    reader: &mut io::BufReader<fs::File>,
-    mem_map: &mut BTreeMap<String, CommandPos>,
+    is_newest: bool,
+    mem_map: &mut BTreeMap<Vec<u8>, CommandPos>,
 ) -> Result<u64> {
-    let mut pos = reader.seek(io::SeekFrom::Start(0))?;
-    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+    let file_path = log_path(path, file_no);
+    let mut reader = io::BufReader::new(fs::File::open(&file_path)?);
+    let file_len = reader.get_ref().metadata()?.len();
     let mut uncompacted_bytes = 0;
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set(key, _) => {
-                if let Some(old_cmd) = mem_map.insert(key, (file_no, pos, new_pos).into()) {
+    let mut framing = 0;
+    let mut apply = |cmd: Command, pos: u64, new_pos: u64| {
+        let mut cmd_pos = CommandPos::from((file_no, pos, new_pos));
+        cmd_pos.expires_at = cmd.expires_at();
+        match cmd {
+            Command::Set(key, _) | Command::SetExpiring(key, _, _) => {
+                if let Some(old_cmd) = mem_map.insert(key, cmd_pos) {
                     uncompacted_bytes += old_cmd.len;
                 }
             }
@@ -258,9 +826,81 @@ fn intialise_mem_map(
             }
             _ => {}
         }
-        pos = new_pos;
+    };
+
+    let torn_at = match read_log_header(&mut reader)? {
+        LogHeader::Torn => Some(0),
+        LogHeader::Legacy => {
+            reader.seek(io::SeekFrom::Start(0))?;
+            let mut pos = 0;
+            let mut stream =
+                serde_json::Deserializer::from_reader(reader).into_iter::<LegacyCommand>();
+            loop {
+                match stream.next() {
+                    Some(Ok(cmd)) => {
+                        let new_pos = stream.byte_offset() as u64;
+                        if let Some(cmd) = cmd.into_command() {
+                            apply(cmd, pos, new_pos);
+                        }
+                        pos = new_pos;
+                    }
+                    Some(Err(ref e)) if e.is_eof() => break Some(pos),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => break None,
+                }
+            }
+        }
+        LogHeader::Versioned(version) if version == LOG_VERSION || version == JSON_LOG_VERSION => {
+            let mut pos = LOG_HEADER_LEN;
+            loop {
+                match read_record(&mut reader, version, file_len - pos)? {
+                    RecordRead::Valid(record) => {
+                        let new_pos = pos + record.len() as u64;
+                        if is_batch(version, &record) {
+                            framing += RECORD_HEADER_LEN as u64;
+                        }
+                        match decode_commands(version, &record) {
+                            Some(cmds) => {
+                                for (cmd, start, end) in cmds {
+                                    apply(cmd, pos + start, pos + end);
+                                }
+                            }
+                            // A crash only ever tears the last write, so records
+                            // past a damaged one were acknowledged and must not
+                            // be cut off with it.
+                            None if valid_record_follows(
+                                &mut reader,
+                                version,
+                                file_len - new_pos,
+                            )? =>
+                            {
+                                return Err(KvsError::CorruptedLogError(file_no, pos))
+                            }
+                            // A batch is applied either as a whole or not at all.
+                            None => break Some(pos),
+                        }
+                        pos = new_pos;
+                    }
+                    // The record runs past the end of the file, as the last
+                    // write before a crash does.
+                    RecordRead::Torn => break Some(pos),
+                    RecordRead::Eof => break None,
+                }
+            }
+        }
+        LogHeader::Versioned(version) => return Err(KvsError::UnsupportedLogVersion(version)),
+    };
+
+    if let Some(pos) = torn_at {
+        if !is_newest {
+            return Err(KvsError::CorruptedLogError(file_no, pos));
+        }
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)?
+            .set_len(pos)?;
     }
-    Ok(uncompacted_bytes)
+    Ok(uncompacted_bytes + framing)
 }
 
 fn get_sorted_file_list(path: &path::Path) -> Result<Vec<u64>> {
@@ -283,46 +923,523 @@ fn log_path(path: &path::Path, file_no: u64) -> path::PathBuf {
     path.join(format!("{}.db", file_no))
 }
 
+fn compaction_path(path: &path::Path, file_no: u64) -> path::PathBuf {
+    path.join(format!("{}.compact", file_no))
+}
+
+fn hint_path(path: &path::Path, file_no: u64) -> path::PathBuf {
+    path.join(format!("{}.hint", file_no))
+}
+
+/// A hint file sits next to a compacted log and lists the position of every
+/// record in it, so the log does not have to be replayed on open. It holds
+/// `HINT_MAGIC | version | data_len` followed by `key_len | key | start | len |
+/// expires_at` entries and a trailing crc32 of everything before it. `data_len`
+/// is the size of the log the hint was written for, the other fields are `u64`s
+/// and an `expires_at` of zero means the key does not expire. Version 1 entries
+/// have no `expires_at`.
+const HINT_MAGIC: &[u8; 4] = b"KVSH";
+const HINT_VERSION: u8 = 2;
+const HINT_HEADER_LEN: usize = 13;
+
+fn write_hint_file(
+    path: &path::Path,
+    file_no: u64,
+    data_len: u64,
+    hints: &[(Vec<u8>, CommandPos)],
+) -> Result<()> {
+    let mut content = Vec::with_capacity(HINT_HEADER_LEN + hints.len() * 40);
+    content.extend_from_slice(HINT_MAGIC);
+    content.push(HINT_VERSION);
+    content.extend_from_slice(&data_len.to_le_bytes());
+    for (key, cmd_pos) in hints {
+        content.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        content.extend_from_slice(key);
+        content.extend_from_slice(&cmd_pos.start.to_le_bytes());
+        content.extend_from_slice(&cmd_pos.len.to_le_bytes());
+        content.extend_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+    }
+    let crc = checksum(&[&content]);
+    content.extend_from_slice(&crc.to_le_bytes());
+
+    let temp_path = path.join(format!("{}.hint.compact", file_no));
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(&content)?;
+    file.sync_all()?;
+    fs::rename(temp_path, hint_path(path, file_no))?;
+    Ok(())
+}
+
+/// Loads the positions listed in the hint file of a log into `mem_map` and returns the
+/// number of stale bytes found. Returns `None` without touching `mem_map` if there is no
+/// hint file or it does not match the log, in which case the log has to be replayed.
+fn load_hint_file(
+    path: &path::Path,
+    file_no: u64,
+    mem_map: &mut BTreeMap<Vec<u8>, CommandPos>,
+) -> Result<Option<u64>> {
+    let content = match fs::read(hint_path(path, file_no)) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let data_len = fs::metadata(log_path(path, file_no))?.len();
+    let hints = match parse_hint_file(file_no, data_len, &content) {
+        Some(hints) => hints,
+        None => return Ok(None),
+    };
+
+    let mut uncompacted_bytes = 0;
+    for (key, cmd_pos) in hints {
+        if let Some(old_cmd) = mem_map.insert(key, cmd_pos) {
+            uncompacted_bytes += old_cmd.len;
+        }
+    }
+    Ok(Some(uncompacted_bytes))
+}
+
+fn parse_hint_file(
+    file_no: u64,
+    data_len: u64,
+    content: &[u8],
+) -> Option<Vec<(Vec<u8>, CommandPos)>> {
+    if content.len() < HINT_HEADER_LEN + 4 || !content.starts_with(HINT_MAGIC) {
+        return None;
+    }
+    let entry_len = match content[HINT_MAGIC.len()] {
+        1 => 16,
+        HINT_VERSION => 24,
+        _ => return None,
+    };
+    let (body, crc) = content.split_at(content.len() - 4);
+    if u32_at(crc, 0) != checksum(&[body]) || u64_at(body, 5) != data_len {
+        return None;
+    }
+
+    let mut hints = Vec::new();
+    let mut pos = HINT_HEADER_LEN;
+    while pos < body.len() {
+        if body.len() - pos < 4 {
+            return None;
+        }
+        let key_len = u32_at(body, pos) as usize;
+        pos += 4;
+        if body.len() - pos < key_len + entry_len {
+            return None;
+        }
+        let key = body[pos..pos + key_len].to_vec();
+        pos += key_len;
+        let start = u64_at(body, pos);
+        let len = u64_at(body, pos + 8);
+        let expires_at = if entry_len > 16 {
+            Some(u64_at(body, pos + 16)).filter(|&expires_at| expires_at != 0)
+        } else {
+            None
+        };
+        pos += entry_len;
+        if start < LOG_HEADER_LEN || start.checked_add(len).is_none_or(|end| end > data_len) {
+            return None;
+        }
+        let mut cmd_pos = CommandPos::from((file_no, start, start + len));
+        cmd_pos.expires_at = expires_at;
+        hints.push((key, cmd_pos));
+    }
+    Some(hints)
+}
+
+/// Removes every log older than `safe_point`, along with its hint file.
+fn remove_stale_logs(path: &path::Path, safe_point: u64) -> Result<()> {
+    for file_no in get_sorted_file_list(path)? {
+        if file_no >= safe_point {
+            break;
+        }
+        fs::remove_file(log_path(path, file_no))?;
+        let hint_path = hint_path(path, file_no);
+        if hint_path.exists() {
+            fs::remove_file(hint_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the output of a compaction that was interrupted before it completed.
+fn remove_compaction_leftovers(path: &path::Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if entry_path.is_file() && entry_path.extension() == Some("compact".as_ref()) {
+            fs::remove_file(entry_path)?;
+        }
+    }
+    Ok(())
+}
+
 fn new_db_file(
     path: &path::Path,
     file_no: u64,
     reader: &KvReader,
 ) -> Result<io::BufWriter<fs::File>> {
     let path = log_path(path, file_no);
-    let writer = io::BufWriter::new(
+    let writer = new_log_file(&path)?;
+    reader
+        .readers
+        .borrow_mut()
+        .insert(file_no, LogReader::open(&path)?);
+    Ok(writer)
+}
+
+/// Creates a log file and writes the format header to it.
+fn new_log_file(path: &path::Path) -> Result<io::BufWriter<fs::File>> {
+    let mut writer = io::BufWriter::new(
         fs::OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
-            .open(&path)?,
+            .open(path)?,
     );
-    reader
-        .readers
-        .borrow_mut()
-        .insert(file_no, io::BufReader::new(fs::File::open(&path)?));
+    writer.write_all(LOG_MAGIC)?;
+    writer.write_all(&[LOG_VERSION])?;
+    writer.flush()?;
     Ok(writer)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Log files start with `LOG_MAGIC` followed by a format version byte.
+/// Files without the magic are legacy logs of back to back JSON commands.
+const LOG_MAGIC: &[u8; 4] = b"KVSL";
+const LOG_HEADER_LEN: u64 = 5;
+/// In version 2 every record is `crc32 | type | key_len | value_len | key | value`.
+/// The lengths are little endian `u32`s and the checksum covers everything after it.
+/// A batch record has no key, and its value is the records of the batch. The
+/// value of a set with an expiry starts with the deadline, a `u64` of unix milliseconds.
+const LOG_VERSION: u8 = 2;
+/// In version 1 every record is framed as `crc32 | len | payload`. Both header
+/// fields are little endian `u32`s and the checksum covers `len` and the JSON payload.
+const JSON_LOG_VERSION: u8 = 1;
+const LEGACY_LOG_VERSION: u8 = 0;
+const RECORD_HEADER_LEN: usize = 13;
+const JSON_RECORD_HEADER_LEN: usize = 8;
+const RECORD_SET: u8 = 1;
+const RECORD_RM: u8 = 2;
+const RECORD_BATCH: u8 = 3;
+const RECORD_SET_EXPIRING: u8 = 4;
+
+enum LogHeader {
+    Versioned(u8),
+    Legacy,
+    Torn,
+}
+
+fn read_log_header<R: Read>(reader: &mut R) -> Result<LogHeader> {
+    let mut header = [0; LOG_HEADER_LEN as usize];
+    let read = read_full(reader, &mut header)?;
+    if read == header.len() && header.starts_with(LOG_MAGIC) {
+        Ok(LogHeader::Versioned(header[LOG_MAGIC.len()]))
+    } else if read > 0
+        && read < header.len()
+        && LOG_MAGIC.starts_with(&header[..read.min(LOG_MAGIC.len())])
+    {
+        Ok(LogHeader::Torn)
+    } else {
+        Ok(LogHeader::Legacy)
+    }
+}
+
+enum RecordRead {
+    Valid(Vec<u8>),
+    Torn,
+    Eof,
+}
+
+/// Reads the next framed record of a log with the given version, header included.
+/// `remaining` is the number of bytes left in the file, which bounds the length a
+/// sane header may claim.
+fn read_record<R: Read>(reader: &mut R, version: u8, remaining: u64) -> Result<RecordRead> {
+    let header_len = if version == JSON_LOG_VERSION {
+        JSON_RECORD_HEADER_LEN
+    } else {
+        RECORD_HEADER_LEN
+    };
+    let mut header = [0; RECORD_HEADER_LEN];
+    let header = &mut header[..header_len];
+    match read_full(reader, header)? {
+        0 => return Ok(RecordRead::Eof),
+        read if read < header_len => return Ok(RecordRead::Torn),
+        _ => {}
+    }
+    let body_len = if version == JSON_LOG_VERSION {
+        u64::from(u32_at(header, 4))
+    } else {
+        u64::from(u32_at(header, 5)) + u64::from(u32_at(header, 9))
+    };
+    if header_len as u64 + body_len > remaining {
+        return Ok(RecordRead::Torn);
+    }
+    let mut record = header.to_vec();
+    record.resize(header_len + body_len as usize, 0);
+    reader.read_exact(&mut record[header_len..])?;
+    Ok(RecordRead::Valid(record))
+}
+
+fn is_batch(version: u8, record: &[u8]) -> bool {
+    version == LOG_VERSION && record.get(4) == Some(&RECORD_BATCH)
+}
+
+/// Decodes the commands held by a framed record along with their positions inside
+/// of it, returning `None` if the record is damaged.
+fn decode_commands(version: u8, record: &[u8]) -> Option<Vec<PlacedCommand>> {
+    if is_batch(version, record) {
+        decode_batch(record)
+    } else {
+        decode_record(version, record).map(|cmd| vec![(cmd, 0, record.len() as u64)])
+    }
+}
+
+/// Tells whether a valid record follows a damaged one, reading on from right
+/// after it and skipping over any other damaged records.
+fn valid_record_follows<R: Read>(reader: &mut R, version: u8, mut remaining: u64) -> Result<bool> {
+    loop {
+        match read_record(reader, version, remaining)? {
+            RecordRead::Valid(record) => {
+                if decode_commands(version, &record).is_some() {
+                    return Ok(true);
+                }
+                remaining -= record.len() as u64;
+            }
+            RecordRead::Torn | RecordRead::Eof => return Ok(false),
+        }
+    }
+}
+
+fn encode_record(cmd: &Command) -> Result<Vec<u8>> {
+    let (record_type, key, value) = match cmd {
+        Command::Set(key, value) => (RECORD_SET, key, value.to_vec()),
+        Command::SetExpiring(key, value, expires_at) => {
+            let mut deadline_and_value = expires_at.to_le_bytes().to_vec();
+            deadline_and_value.extend_from_slice(value);
+            (RECORD_SET_EXPIRING, key, deadline_and_value)
+        }
+        Command::Rm(key) => (RECORD_RM, key, Vec::new()),
+        Command::Batch(_) => return Err(KvsError::UnexpectedCommandError),
+    };
+    if key.len() > u32::MAX as usize || value.len() > u32::MAX as usize {
+        return Err(KvsError::InvalidRequestError(
+            "Command too large for a log record".into(),
+        ));
+    }
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + key.len() + value.len());
+    record.extend_from_slice(&[0; 4]);
+    record.push(record_type);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(&value);
+    let crc = checksum(&[&record[4..]]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    Ok(record)
+}
+
+/// A command along with where its record starts and ends inside of the record
+/// holding it, which is itself unless it is part of a batch.
+type PlacedCommand = (Command, u64, u64);
+
+/// Encodes commands as a single batch record, so that recovery either applies all
+/// of them or none. Returns the record along with the position of every command
+/// inside of it.
+fn encode_batch(cmds: Vec<Command>) -> Result<(Vec<u8>, Vec<PlacedCommand>)> {
+    let mut record = vec![0; RECORD_HEADER_LEN];
+    let mut positions = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let start = record.len() as u64;
+        record.extend_from_slice(&encode_record(&cmd)?);
+        positions.push((cmd, start, record.len() as u64));
+    }
+    let body_len = record.len() - RECORD_HEADER_LEN;
+    if body_len > u32::MAX as usize {
+        return Err(KvsError::InvalidRequestError(
+            "Batch too large for a log record".into(),
+        ));
+    }
+    record[4] = RECORD_BATCH;
+    record[9..13].copy_from_slice(&(body_len as u32).to_le_bytes());
+    let crc = checksum(&[&record[4..]]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    Ok((record, positions))
+}
+
+/// Splits a batch record into the commands it holds and their positions inside
+/// of it, returning `None` if the batch or any record in it is damaged.
+fn decode_batch(record: &[u8]) -> Option<Vec<PlacedCommand>> {
+    if u32_at(record, 0) != checksum(&[&record[4..]]) {
+        return None;
+    }
+    let mut cmds = Vec::new();
+    let mut pos = RECORD_HEADER_LEN;
+    while pos < record.len() {
+        if record.len() - pos < RECORD_HEADER_LEN {
+            return None;
+        }
+        let end = pos
+            + RECORD_HEADER_LEN
+            + u32_at(record, pos + 5) as usize
+            + u32_at(record, pos + 9) as usize;
+        if end > record.len() {
+            return None;
+        }
+        let cmd = decode_record(LOG_VERSION, &record[pos..end])?;
+        cmds.push((cmd, pos as u64, end as u64));
+        pos = end;
+    }
+    Some(cmds)
+}
+
+/// Decodes a record read from a log of the given version, returning `None`
+/// if it fails its checksum or does not hold a valid command.
+fn decode_record(version: u8, record: &[u8]) -> Option<Command> {
+    match version {
+        LEGACY_LOG_VERSION => serde_json::from_slice::<LegacyCommand>(record)
+            .ok()?
+            .into_command(),
+        JSON_LOG_VERSION => {
+            if record.len() < JSON_RECORD_HEADER_LEN {
+                return None;
+            }
+            let (header, payload) = record.split_at(JSON_RECORD_HEADER_LEN);
+            if u32_at(header, 0) != checksum(&[&header[4..], payload]) {
+                return None;
+            }
+            serde_json::from_slice::<LegacyCommand>(payload)
+                .ok()?
+                .into_command()
+        }
+        LOG_VERSION => {
+            if record.len() < RECORD_HEADER_LEN || u32_at(record, 0) != checksum(&[&record[4..]]) {
+                return None;
+            }
+            let key_len = u32_at(record, 5) as usize;
+            let body = &record[RECORD_HEADER_LEN..];
+            if body.len() < key_len {
+                return None;
+            }
+            let (key, value) = body.split_at(key_len);
+            let key = key.to_vec();
+            match record[4] {
+                RECORD_SET => Some(Command::Set(key, value.to_vec())),
+                RECORD_SET_EXPIRING if value.len() >= 8 => {
+                    let expires_at = u64_at(value, 0);
+                    Some(Command::SetExpiring(key, value[8..].to_vec(), expires_at))
+                }
+                RECORD_RM => Some(Command::Rm(key)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn checksum(parts: &[&[u8]]) -> u32 {
+    let mut hasher = Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Like `read_exact`, but stops at EOF and returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+struct LogReader {
+    reader: io::BufReader<fs::File>,
+    version: u8,
+}
+
+impl LogReader {
+    fn open(path: &path::Path) -> Result<Self> {
+        let mut reader = io::BufReader::new(fs::File::open(path)?);
+        let version = match read_log_header(&mut reader)? {
+            LogHeader::Versioned(version) => version,
+            LogHeader::Legacy | LogHeader::Torn => LEGACY_LOG_VERSION,
+        };
+        Ok(LogReader { reader, version })
+    }
+}
+
+#[derive(Debug)]
 pub enum Command {
+    Set(Vec<u8>, Vec<u8>),
+    Rm(Vec<u8>),
+    Batch(Vec<Command>),
+    SetExpiring(Vec<u8>, Vec<u8>, u64),
+}
+
+impl Command {
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::SetExpiring(_, _, expires_at) => Some(*expires_at),
+            _ => None,
+        }
+    }
+}
+
+/// A command as stored by the JSON log formats, which only held strings.
+#[derive(Deserialize)]
+enum LegacyCommand {
     Set(String, String),
     Rm(String),
-    Get(String),
+    Get(IgnoredAny),
 }
 
-#[derive(Clone)]
+impl LegacyCommand {
+    fn into_command(self) -> Option<Command> {
+        match self {
+            LegacyCommand::Set(key, value) => Some(Command::Set(key.into(), value.into())),
+            LegacyCommand::Rm(key) => Some(Command::Rm(key.into())),
+            LegacyCommand::Get(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
 struct CommandPos {
     file_no: u64,
     start: u64,
     len: u64,
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 impl From<(u64, u64, u64)> for CommandPos {
     fn from((file_no, pos, new_pos): (u64, u64, u64)) -> Self {
         CommandPos {
-            file_no: file_no,
+            file_no,
             start: pos,
             len: new_pos - pos,
+            expires_at: None,
         }
     }
 }
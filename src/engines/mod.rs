@@ -1,18 +1,186 @@
 use crate::errors::Result;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::vec;
+
+/// A key value storage engine. Keys and values are arbitrary bytes; the methods
+/// taking `String`s are a convenience layer over the byte-oriented ones, and fail
+/// with `StringParseError` when a stored key or value is not valid UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
     /// Sets a key-value pair into the Key value store
     /// If the store did not have this key present, the key is inserted
     /// If the store did have this key, the value is updated.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets a key-value pair that expires after `ttl`. Expired keys are treated
+    /// as missing, and setting the key again without a TTL removes the expiry.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
 
     /// Returns the value corresponding to the key.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
     /// Removes a key from the map
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Applies all the writes in `batch` atomically, in order.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Makes every write that has returned durable on disk, whatever the engine
+    /// would otherwise sync.
+    fn flush(&self) -> Result<()>;
+
+    /// Atomically sets the key to `new` if its current value is `expected`, where
+    /// `None` stands for a missing key on both sides.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome<Vec<u8>>>;
+
+    /// Returns the key-value pairs whose keys fall in `range`, ordered by key.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<vec::IntoIter<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns the key-value pairs whose keys start with `prefix`, ordered by key.
+    fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        options: ScanOptions,
+    ) -> Result<vec::IntoIter<(Vec<u8>, Vec<u8>)>> {
+        self.scan_bytes(prefix_range(prefix), options)
+    }
+
+    /// Sets a string value, see `set_bytes`.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets a string value that expires after `ttl`, see `set_bytes_with_ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Returns the string value of a key, see `get_bytes`.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a key, see `remove_bytes`.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Swaps string values, see `compare_and_swap_bytes`.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<CasOutcome> {
+        let outcome = self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+        Ok(CasOutcome {
+            succeeded: outcome.succeeded,
+            current: match outcome.current {
+                Some(current) => Some(String::from_utf8(current)?),
+                None => None,
+            },
+        })
+    }
+
+    /// Returns the string pairs whose keys fall in `range`, see `scan_bytes`.
+    fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<vec::IntoIter<(String, String)>> {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        string_pairs(self.scan_bytes(range, options)?)
+    }
+
+    /// Returns the string pairs whose keys start with `prefix`, see `scan_prefix_bytes`.
+    fn scan_prefix(
+        &self,
+        prefix: String,
+        options: ScanOptions,
+    ) -> Result<vec::IntoIter<(String, String)>> {
+        string_pairs(self.scan_prefix_bytes(prefix.into_bytes(), options)?)
+    }
+}
+
+/// The outcome of `KvsEngine::compare_and_swap`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CasOutcome<V = String> {
+    /// Whether the value matched and was swapped.
+    pub succeeded: bool,
+    /// The value of the key once the operation completed.
+    pub current: Option<V>,
+}
+
+/// Returns the current time in milliseconds since the unix epoch.
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// Returns the deadline, in unix milliseconds, of a key set now with the given TTL.
+pub(crate) fn deadline_after(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    unix_millis().saturating_add(ttl)
+}
+
+/// Returns the range of keys starting with `prefix`: from the prefix itself up to
+/// the smallest key that is greater than all of them.
+fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+/// Converts a bound on string keys to one on their bytes, which sort the same way.
+fn bytes_bound(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone().into_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone().into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn string_pairs(
+    pairs: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+) -> Result<vec::IntoIter<(String, String)>> {
+    let pairs = pairs
+        .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(pairs.into_iter())
 }
 
+mod batch;
 mod kvstore;
+mod options;
 mod sledstore;
 
+pub use self::batch::WriteBatch;
 pub use self::kvstore::KvStore;
+pub use self::options::{KvStoreOptions, ScanOptions, SyncPolicy};
 pub use self::sledstore::SledStore;
@@ -0,0 +1,135 @@
+use crate::errors::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Tuning options for a `KvStore`, passed to `KvStore::open_with`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) compaction_threshold: u64,
+    pub(crate) compaction_ratio: Option<f64>,
+    pub(crate) max_file_size: Option<u64>,
+    pub(crate) sync_policy: SyncPolicy,
+}
+
+impl KvStoreOptions {
+    /// Returns the default options: compaction once 1 MB of the log is stale,
+    /// no limit on the size of the active file and no fsync.
+    pub fn new() -> Self {
+        KvStoreOptions {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: None,
+            max_file_size: None,
+            sync_policy: SyncPolicy::Never,
+        }
+    }
+
+    /// Sets how many bytes of the log must be stale before it is compacted.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Additionally requires stale records to make up at least this fraction
+    /// of the log, between 0 and 1, before it is compacted.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = Some(ratio);
+        self
+    }
+
+    /// Sets the size in bytes after which writes roll over to a new log file.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Sets when writes are fsynced to disk.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(ratio) = self.compaction_ratio {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                return Err(KvsError::Err(format!(
+                    "Compaction ratio must be in (0, 1], got {}",
+                    ratio
+                )));
+            }
+        }
+        if self.max_file_size == Some(0) {
+            return Err(KvsError::Err("Max file size must not be zero".into()));
+        }
+        if self.sync_policy == SyncPolicy::Interval(Duration::from_millis(0)) {
+            return Err(KvsError::Err("Sync interval must not be zero".into()));
+        }
+        Ok(())
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Controls when a `KvStore` fsyncs its active log file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave it to the operating system; writes are only flushed.
+    Never,
+    /// Fsync before every write returns.
+    Always,
+    /// Fsync from a background thread at most this long after a write.
+    Interval(Duration),
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    /// Parses `never`, `always` or an interval in milliseconds.
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => s
+                .parse::<u64>()
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .map_err(|_| {
+                    format!(
+                        "Invalid sync policy {}, expected never, always or milliseconds",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+/// Options for `KvsEngine::scan` and `KvsEngine::scan_prefix`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanOptions {
+    pub(crate) limit: Option<usize>,
+    pub(crate) reverse: bool,
+}
+
+impl ScanOptions {
+    /// Returns the default options: every matching pair, in ascending key order.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops the scan after this many pairs.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Returns the pairs in descending key order.
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+}
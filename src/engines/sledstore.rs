@@ -1,10 +1,23 @@
 use sled::Db;
 use std::borrow::Borrow;
+use std::ops::RangeBounds;
 use std::path;
-use std::str;
+use std::time::Duration;
+use std::vec;
 
-use super::KvsEngine;
+use super::batch::BatchOp;
+use super::{deadline_after, unix_millis, CasOutcome, KvsEngine, ScanOptions, WriteBatch};
 use crate::{KvsError, Result};
+
+/// Values are stored as they are, unless they start with `TAGGED` or have a TTL.
+/// Those are stored as `TAGGED | tag | ...`: `TAG_PLAIN` is followed by the value,
+/// and `TAG_EXPIRING` by the deadline, a big endian `u64` of unix milliseconds,
+/// and then the value. No UTF-8 string starts with `TAGGED`, so values written
+/// back when only strings could be stored read the same.
+const TAGGED: u8 = 0xff;
+const TAG_PLAIN: u8 = 0;
+const TAG_EXPIRING: u8 = 1;
+
 #[derive(Debug, Clone)]
 pub struct SledStore {
     pub store: Db,
@@ -19,8 +32,8 @@ impl SledStore {
 }
 
 impl KvsEngine for SledStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        match self.store.insert(key, &value[..]) {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.store.insert(key, encode_value(value, None)) {
             Ok(_) => match self.store.flush() {
                 Ok(_) => Ok(()),
                 Err(e) => Err(KvsError::SledEngineError(e)),
@@ -29,20 +42,30 @@ impl KvsEngine for SledStore {
         }
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let stored = encode_value(value, Some(deadline_after(ttl)));
+        self.store.insert(key, stored)?;
+        self.store.flush()?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         match self.store.get(key) {
             Ok(o) => match o {
-                Some(value) => return Ok(Some(str::from_utf8(value.borrow())?.to_string())),
+                Some(value) => decode_value(value.borrow()),
                 None => Ok(None),
             },
             Err(e) => Err(KvsError::SledEngineError(e)),
         }
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let not_found = || KvsError::NotFoundError(String::from_utf8_lossy(&key).into_owned());
         match self.store.remove(&key) {
             Ok(opt) => match opt {
-                None => Err(KvsError::NotFoundError(key)),
+                None => Err(not_found()),
+                // Removing an expired value only cleans it up.
+                Some(ref value) if decode_value(value)?.is_none() => Err(not_found()),
                 Some(_) => match self.store.flush() {
                     Ok(_) => Ok(()),
                     Err(e) => Err(KvsError::SledEngineError(e)),
@@ -51,4 +74,117 @@ impl KvsEngine for SledStore {
             Err(e) => Err(KvsError::SledEngineError(e)),
         }
     }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        for op in batch.ops {
+            match op {
+                BatchOp::Set(key, value) => sled_batch.insert(key, encode_value(value, None)),
+                BatchOp::Remove(key) => sled_batch.remove(key),
+            }
+        }
+        self.store.apply_batch(sled_batch)?;
+        self.store.flush()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()?;
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        // The stored value has to be compared rather than `expected`, since it
+        // may carry an expiry or have expired. Retry if it changes in between.
+        let new_stored = new.clone().map(|value| encode_value(value, None));
+        let mut stored = self.store.get(&key)?;
+        loop {
+            let current = match &stored {
+                Some(value) => decode_value(value)?,
+                None => None,
+            };
+            if current != expected {
+                return Ok(CasOutcome {
+                    succeeded: false,
+                    current,
+                });
+            }
+            let swapped = self
+                .store
+                .compare_and_swap(&key, stored.as_ref(), new_stored.clone())?;
+            match swapped {
+                Ok(()) => {
+                    self.store.flush()?;
+                    return Ok(CasOutcome {
+                        succeeded: true,
+                        current: new,
+                    });
+                }
+                Err(e) => stored = e.current,
+            }
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<vec::IntoIter<(Vec<u8>, Vec<u8>)>> {
+        let iter = self.store.range(range);
+        let iter: Box<dyn Iterator<Item = _>> = if options.reverse {
+            Box::new(iter.rev())
+        } else {
+            Box::new(iter)
+        };
+        let mut pairs = Vec::new();
+        for pair in iter {
+            if Some(pairs.len()) == options.limit {
+                break;
+            }
+            let (key, value) = pair?;
+            if let Some(value) = decode_value(&value)? {
+                pairs.push((key.to_vec(), value));
+            }
+        }
+        Ok(pairs.into_iter())
+    }
+}
+
+fn encode_value(value: Vec<u8>, expires_at: Option<u64>) -> Vec<u8> {
+    match expires_at {
+        None if value.first() != Some(&TAGGED) => value,
+        None => {
+            let mut stored = vec![TAGGED, TAG_PLAIN];
+            stored.extend_from_slice(&value);
+            stored
+        }
+        Some(expires_at) => {
+            let mut stored = vec![TAGGED, TAG_EXPIRING];
+            stored.extend_from_slice(&expires_at.to_be_bytes());
+            stored.extend_from_slice(&value);
+            stored
+        }
+    }
+}
+
+/// Decodes a stored value, returning `None` if it has expired.
+fn decode_value(stored: &[u8]) -> Result<Option<Vec<u8>>> {
+    match stored {
+        [TAGGED, TAG_PLAIN, value @ ..] => Ok(Some(value.to_vec())),
+        [TAGGED, TAG_EXPIRING, rest @ ..] if rest.len() >= 8 => {
+            let mut deadline = [0; 8];
+            deadline.copy_from_slice(&rest[..8]);
+            if u64::from_be_bytes(deadline) <= unix_millis() {
+                return Ok(None);
+            }
+            Ok(Some(rest[8..].to_vec()))
+        }
+        [TAGGED, ..] => Err(KvsError::Err("Invalid tagged value".into())),
+        _ => Ok(Some(stored.to_vec())),
+    }
 }
@@ -4,6 +4,7 @@ use sled;
 use std::io;
 use std::result;
 use std::str::Utf8Error;
+use std::string::FromUtf8Error;
 
 /// KVS Error type
 #[derive(Debug, Fail)]
@@ -12,6 +13,26 @@ pub enum KvsError {
     IOError(io::Error),
     #[fail(display = "KVS command serialization/Deserialization error")]
     SerDeError(serde_json::error::Error),
+    #[fail(display = "KVS protocol encoding error: {}", _0)]
+    ProtocolError(bincode::Error),
+    #[fail(display = "Protocol frame of {} bytes exceeds the size limit", _0)]
+    FrameTooLargeError(u64),
+    #[fail(display = "HTTP protocol error: {}", _0)]
+    HttpProtocolError(String),
+    #[fail(display = "RESP protocol error: {}", _0)]
+    RespProtocolError(String),
+    #[fail(display = "Connection closed by peer")]
+    ConnectionClosedError,
+    #[fail(display = "Incompatible protocol: {}", _0)]
+    IncompatibleProtocolError(String),
+    #[fail(display = "The server does not support {}", _0)]
+    UnsupportedFeatureError(String),
+    #[fail(display = "Server busy, try again later")]
+    ServerBusyError,
+    #[fail(display = "Thread pool is saturated")]
+    PoolSaturatedError,
+    #[fail(display = "Thread pool did not stop in time, {} threads still run", _0)]
+    PoolShutdownTimeoutError(usize),
     #[fail(display = "Key not found: {}", _0)]
     NotFoundError(String),
     #[fail(display = "Path Error")]
@@ -20,11 +41,19 @@ pub enum KvsError {
     // WalkDirError(walkdir::Error),
     #[fail(display = "Error while compacting database log")]
     CompactionError(),
+    #[fail(display = "Corrupted record in log file {}.db at offset {}", _0, _1)]
+    CorruptedLogError(u64, u64),
+    #[fail(display = "Unsupported log format version: {}", _0)]
+    UnsupportedLogVersion(u8),
     #[fail(display = "Unexpected Command type found")]
     UnexpectedCommandError,
+    #[fail(display = "Invalid request: {}", _0)]
+    InvalidRequestError(String),
+    #[fail(display = "Engine error on the server: {}", _0)]
+    EngineError(String),
     #[fail(display = "Error in sled engine: {}", _0)]
     SledEngineError(sled::Error),
-    #[fail(display = "Error parsing string for sled engine")]
+    #[fail(display = "Stored bytes are not a valid UTF-8 string")]
     StringParseError(Utf8Error),
     #[fail(display = "KVS misc error")]
     Err(String),
@@ -42,6 +71,12 @@ impl From<serde_json::error::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(error: bincode::Error) -> Self {
+        KvsError::ProtocolError(error)
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(error: sled::Error) -> Self {
         KvsError::SledEngineError(error)
@@ -54,5 +89,11 @@ impl From<Utf8Error> for KvsError {
     }
 }
 
+impl From<FromUtf8Error> for KvsError {
+    fn from(error: FromUtf8Error) -> Self {
+        KvsError::StringParseError(error.utf8_error())
+    }
+}
+
 /// KVS Result type
 pub type Result<T> = result::Result<T, KvsError>;
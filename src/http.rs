@@ -0,0 +1,315 @@
+//! An HTTP/1.1 gateway to the store, for scripts and browsers that cannot speak
+//! the `KvsClient` protocol:
+//!
+//! - `GET /keys/{key}` returns the value as the response body.
+//! - `PUT /keys/{key}` sets the key to the request body.
+//! - `DELETE /keys/{key}` removes the key.
+//! - `GET /keys?prefix={prefix}&limit={limit}` lists the matching pairs as JSON.
+//!
+//! Keys in paths and queries are percent-decoded. Missing keys get a 404.
+
+use crate::{KvsEngine, KvsError, Result, ScanOptions};
+use serde_json::json;
+use slog::Logger;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::TcpStream;
+
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: usize = 512 * 1024 * 1024;
+
+struct Request {
+    method: String,
+    target: Vec<u8>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn empty(status: u16) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: Vec::new(),
+            allow: None,
+        }
+    }
+
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Response {
+            allow: Some(allow),
+            ..Self::error(405, "Method not allowed")
+        }
+    }
+}
+
+/// Serves the HTTP requests sent on a connection until either side closes it.
+pub(crate) fn handle_connection<T: KvsEngine>(
+    store: T,
+    log: &Logger,
+    stream: TcpStream,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let req = match read_request(&mut reader) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(KvsError::HttpProtocolError(e)) => {
+                let res = Response::error(400, &e);
+                write_response(&mut writer, &res, false)?;
+                writer.flush()?;
+                return Err(KvsError::HttpProtocolError(e));
+            }
+            Err(e) => return Err(e),
+        };
+        debug!(
+            log,
+            "Received HTTP request: {} {}",
+            req.method,
+            String::from_utf8_lossy(&req.target)
+        );
+        let res = handle_request(&store, &req);
+        write_response(&mut writer, &res, req.keep_alive)?;
+        writer.flush()?;
+        if !req.keep_alive {
+            break;
+        }
+    }
+    debug!(log, "Connection closed by client");
+    Ok(())
+}
+
+fn handle_request<T: KvsEngine>(store: &T, req: &Request) -> Response {
+    let (path, query) = match req.target.iter().position(|byte| *byte == b'?') {
+        Some(pos) => (&req.target[..pos], &req.target[pos + 1..]),
+        None => (&req.target[..], &b""[..]),
+    };
+    let res = if path == b"/keys" {
+        match &req.method[..] {
+            "GET" => list(store, query),
+            _ => return Response::method_not_allowed("GET"),
+        }
+    } else if let Some(key) = path.strip_prefix(b"/keys/") {
+        let key = match percent_decode(key, false) {
+            Some(key) => key,
+            None => return Response::error(400, "Invalid percent-encoding in key"),
+        };
+        match &req.method[..] {
+            "GET" => store.get_bytes(key).map(|value| match value {
+                Some(value) => Response {
+                    status: 200,
+                    content_type: "application/octet-stream",
+                    body: value,
+                    allow: None,
+                },
+                None => Response::error(404, "Key not found"),
+            }),
+            "PUT" => store
+                .set_bytes(key, req.body.clone())
+                .map(|()| Response::empty(204)),
+            "DELETE" => store.remove_bytes(key).map(|()| Response::empty(204)),
+            _ => return Response::method_not_allowed("GET, PUT, DELETE"),
+        }
+    } else {
+        return Response::error(404, "Not found");
+    };
+    res.unwrap_or_else(|e| match e {
+        KvsError::NotFoundError(_) => Response::error(404, "Key not found"),
+        e => Response::error(500, &e.to_string()),
+    })
+}
+
+/// Lists the pairs whose keys start with the `prefix` query parameter, at most
+/// `limit` of them. Keys and values must be valid UTF-8 to be listed as JSON.
+fn list<T: KvsEngine>(store: &T, query: &[u8]) -> Result<Response> {
+    let mut prefix = String::new();
+    let mut options = ScanOptions::new();
+    for param in query.split(|byte| *byte == b'&') {
+        let (name, value) = match param.iter().position(|byte| *byte == b'=') {
+            Some(pos) => (&param[..pos], &param[pos + 1..]),
+            None => (param, &b""[..]),
+        };
+        let value = percent_decode(value, true).and_then(|value| String::from_utf8(value).ok());
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(Response::error(400, "Invalid query parameter")),
+        };
+        match name {
+            b"prefix" => prefix = value,
+            b"limit" => match value.parse() {
+                Ok(limit) => options = options.limit(limit),
+                Err(_) => return Ok(Response::error(400, "Invalid limit")),
+            },
+            _ => {}
+        }
+    }
+    let pairs: Vec<_> = store
+        .scan_prefix(prefix, options)?
+        .map(|(key, value)| json!({ "key": key, "value": value }))
+        .collect();
+    Ok(Response::json(200, pairs.into()))
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn percent_decode(input: &[u8], plus_as_space: bool) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut bytes = input.iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [*bytes.next()?, *bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                output.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' if plus_as_space => output.push(b' '),
+            _ => output.push(*byte),
+        }
+    }
+    Some(output)
+}
+
+/// Reads a request. Returns `None` if the stream ends before it starts.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(protocol_error("Malformed request line")),
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(protocol_error("Unsupported HTTP version")),
+    };
+
+    let mut content_len = 0;
+    let mut headers = 0;
+    loop {
+        let line = read_line(reader)?.ok_or(KvsError::ConnectionClosedError)?;
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(protocol_error("Too many headers"));
+        }
+        let (name, value) = match line.find(':') {
+            Some(pos) => (line[..pos].to_ascii_lowercase(), line[pos + 1..].trim()),
+            None => return Err(protocol_error("Malformed header")),
+        };
+        match &name[..] {
+            "content-length" => match value.parse() {
+                Ok(len) if len <= MAX_BODY_LEN => content_len = len,
+                _ => return Err(protocol_error("Invalid Content-Length")),
+            },
+            "transfer-encoding" => {
+                return Err(protocol_error("Transfer-Encoding is not supported"))
+            }
+            "connection" => {
+                if value.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if value.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut body = Vec::new();
+    reader.take(content_len as u64).read_to_end(&mut body)?;
+    if body.len() < content_len {
+        return Err(KvsError::ConnectionClosedError);
+    }
+    Ok(Some(Request {
+        method: method.to_owned(),
+        target: target.as_bytes().to_vec(),
+        body,
+        keep_alive,
+    }))
+}
+
+/// Reads a line terminated by CRLF, or a bare LF, without the terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("Line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(line) => Ok(Some(line)),
+        Err(_) => Err(protocol_error("Request is not valid UTF-8")),
+    }
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::HttpProtocolError(message.to_owned())
+}
+
+fn write_response<W: Write>(writer: &mut W, res: &Response, keep_alive: bool) -> Result<()> {
+    write!(writer, "HTTP/1.1 {} {}\r\n", res.status, reason(res.status))?;
+    // A 204 response has no body, so it must not describe one.
+    if res.status != 204 {
+        write!(writer, "Content-Type: {}\r\n", res.content_type)?;
+        write!(writer, "Content-Length: {}\r\n", res.body.len())?;
+    }
+    if let Some(allow) = res.allow {
+        write!(writer, "Allow: {}\r\n", allow)?;
+    }
+    if !keep_alive {
+        write!(writer, "Connection: close\r\n")?;
+    }
+    write!(writer, "\r\n")?;
+    writer.write_all(&res.body)?;
+    Ok(())
+}
+
+/// Answers a client the server is too busy to serve.
+pub(crate) fn write_busy<W: Write>(writer: &mut W) -> Result<()> {
+    write_response(writer, &Response::error(503, "Server busy"), false)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
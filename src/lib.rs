@@ -4,17 +4,25 @@
 // extern crate failure;
 // #[macro_use]
 // extern crate failure_derive;
+mod async_client;
+mod async_server;
 mod client;
 mod common;
 mod engines;
 mod errors;
+mod http;
+mod resp;
 mod server;
 pub mod thread_pool;
 
 #[macro_use]
 extern crate slog;
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledStore};
+pub use async_client::AsyncKvsClient;
+pub use async_server::AsyncKvsServer;
+pub use client::{KvsClient, Pipeline};
+pub use engines::{
+    CasOutcome, KvStore, KvStoreOptions, KvsEngine, ScanOptions, SledStore, SyncPolicy, WriteBatch,
+};
 pub use errors::{KvsError, Result};
-pub use server::KvsServer;
+pub use server::{BusyPolicy, KvsServer, Protocol, ShutdownHandle};
 pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
@@ -0,0 +1,405 @@
+//! A front-end speaking the Redis serialization protocol (RESP2), so that Redis
+//! tooling can be pointed at a `KvsServer`. Only the commands listed in
+//! `handle_command` are understood.
+
+use crate::{KvsEngine, KvsError, Result, ScanOptions};
+use slog::Logger;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::time::Duration;
+
+/// Redis rejects bulk strings longer than this, and so do we.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+/// Redis caps inline commands at this length, and we cap every line.
+const MAX_LINE_LEN: usize = 64 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP2 reply.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+/// Serves the RESP commands sent on a connection until the client closes it or
+/// sends `QUIT`.
+pub(crate) fn handle_connection<T: KvsEngine>(
+    store: T,
+    log: &Logger,
+    stream: TcpStream,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(KvsError::RespProtocolError(e)) => {
+                // Like Redis, report the error and close the connection, since
+                // the rest of the stream can no longer be parsed.
+                write_reply(
+                    &mut writer,
+                    &Reply::Error(format!("ERR Protocol error: {}", e)),
+                )?;
+                writer.flush()?;
+                return Err(KvsError::RespProtocolError(e));
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+        debug!(log, "Received RESP command: {}", name);
+        if name == "quit" {
+            write_reply(&mut writer, &Reply::Simple("OK"))?;
+            break;
+        }
+        let reply = handle_command(&store, &name, &args[1..]);
+        write_reply(&mut writer, &reply)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    debug!(log, "Connection closed by client");
+    Ok(())
+}
+
+fn handle_command<T: KvsEngine>(store: &T, name: &str, args: &[Vec<u8>]) -> Reply {
+    let arity_ok = match name {
+        "ping" => args.len() <= 1,
+        "get" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" => !args.is_empty(),
+        "expire" => args.len() == 2,
+        "scan" => !args.is_empty(),
+        "info" => args.len() <= 1,
+        _ => return Reply::Error(format!("ERR unknown command '{}'", name)),
+    };
+    if !arity_ok {
+        return Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+    let reply = match name {
+        "ping" => Ok(match args.first() {
+            Some(message) => Reply::Bulk(Some(message.clone())),
+            None => Reply::Simple("PONG"),
+        }),
+        "get" => store.get_bytes(args[0].clone()).map(Reply::Bulk),
+        "set" => set(store, args),
+        "del" => del(store, args),
+        "exists" => exists(store, args),
+        "expire" => expire(store, args),
+        "scan" => scan(store, args),
+        _ => Ok(info()),
+    };
+    reply.unwrap_or_else(|e| Reply::Error(format!("ERR {}", e)))
+}
+
+/// `SET key value [EX seconds | PX milliseconds]`
+fn set<T: KvsEngine>(store: &T, args: &[Vec<u8>]) -> Result<Reply> {
+    let (key, value) = (args[0].clone(), args[1].clone());
+    let ttl = match &args[2..] {
+        [] => None,
+        [unit, amount] => {
+            let amount = match parse_integer(amount) {
+                Some(amount) if amount > 0 => amount as u64,
+                _ => {
+                    return Ok(Reply::Error(
+                        "ERR invalid expire time in 'set' command".into(),
+                    ))
+                }
+            };
+            match &unit.to_ascii_lowercase()[..] {
+                b"ex" => Some(Duration::from_secs(amount)),
+                b"px" => Some(Duration::from_millis(amount)),
+                _ => return Ok(syntax_error()),
+            }
+        }
+        _ => return Ok(syntax_error()),
+    };
+    match ttl {
+        Some(ttl) => store.set_bytes_with_ttl(key, value, ttl)?,
+        None => store.set_bytes(key, value)?,
+    }
+    Ok(Reply::Simple("OK"))
+}
+
+/// `DEL key [key ...]`, replying with the number of keys removed.
+fn del<T: KvsEngine>(store: &T, args: &[Vec<u8>]) -> Result<Reply> {
+    let mut removed = 0;
+    for key in args {
+        match store.remove_bytes(key.clone()) {
+            Ok(()) => removed += 1,
+            Err(KvsError::NotFoundError(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Reply::Integer(removed))
+}
+
+/// `EXISTS key [key ...]`, replying with the number of keys that exist.
+fn exists<T: KvsEngine>(store: &T, args: &[Vec<u8>]) -> Result<Reply> {
+    let mut found = 0;
+    for key in args {
+        if store.get_bytes(key.clone())?.is_some() {
+            found += 1;
+        }
+    }
+    Ok(Reply::Integer(found))
+}
+
+/// `EXPIRE key seconds`, replying with 1 if the key exists and 0 otherwise.
+/// The value is read and written back with the new expiry, so a concurrent write
+/// to the same key may win over the expiry.
+fn expire<T: KvsEngine>(store: &T, args: &[Vec<u8>]) -> Result<Reply> {
+    let key = args[0].clone();
+    let seconds = match parse_integer(&args[1]) {
+        Some(seconds) => seconds,
+        None => return Ok(not_an_integer()),
+    };
+    let value = match store.get_bytes(key.clone())? {
+        Some(value) => value,
+        None => return Ok(Reply::Integer(0)),
+    };
+    if seconds <= 0 {
+        return match store.remove_bytes(key) {
+            Ok(()) | Err(KvsError::NotFoundError(_)) => Ok(Reply::Integer(1)),
+            Err(e) => Err(e),
+        };
+    }
+    store.set_bytes_with_ttl(key, value, Duration::from_secs(seconds as u64))?;
+    Ok(Reply::Integer(1))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`. The cursor is `0` to start, and
+/// otherwise the hex encoding of the key the next call starts from.
+fn scan<T: KvsEngine>(store: &T, args: &[Vec<u8>]) -> Result<Reply> {
+    let start = if args[0] == b"0" {
+        Bound::Unbounded
+    } else {
+        match decode_hex(&args[0]) {
+            Some(key) => Bound::Included(key),
+            None => return Ok(Reply::Error("ERR invalid cursor".into())),
+        }
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"match") => pattern = Some(value),
+            [name, value] if name.eq_ignore_ascii_case(b"count") => {
+                count = match parse_integer(value) {
+                    Some(count) if count > 0 => count as usize,
+                    Some(_) => return Ok(syntax_error()),
+                    None => return Ok(not_an_integer()),
+                }
+            }
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    // Fetch one more key than needed, to find where the next call starts.
+    let mut keys: Vec<_> = store
+        .scan_bytes(
+            (start, Bound::Unbounded),
+            ScanOptions::new().limit(count + 1),
+        )?
+        .map(|(key, _)| key)
+        .collect();
+    let cursor = if keys.len() > count {
+        encode_hex(&keys.pop().unwrap_or_default())
+    } else {
+        "0".to_owned()
+    };
+    let keys = keys
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(|key| Reply::Bulk(Some(key)))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(cursor.into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
+
+fn info() -> Reply {
+    let info = format!(
+        "# Server\r\nkvs_version:{}\r\nresp_version:2\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    Reply::Bulk(Some(info.into_bytes()))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".into())
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".into())
+}
+
+fn parse_integer(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Matches a Redis glob pattern: `*` matches any bytes, `?` any single byte and
+/// `\` escapes the next byte.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Where matching resumes when the rest of the pattern fails: right after
+    // the last `*`, and at the key byte that `*` is to swallow next. Earlier
+    // stars never need to be revisited.
+    let mut resume = None;
+    while k < key.len() {
+        let width = match &pattern[p..] {
+            [b'*', ..] => {
+                p += 1;
+                resume = Some((p, k));
+                continue;
+            }
+            [b'?', ..] => Some(1),
+            [b'\\', escaped, ..] => Some(2).filter(|_| *escaped == key[k]),
+            [literal, ..] => Some(1).filter(|_| *literal == key[k]),
+            [] => None,
+        };
+        match (width, resume) {
+            (Some(width), _) => {
+                p += width;
+                k += 1;
+            }
+            (None, Some((star_end, swallowed))) => {
+                p = star_end;
+                k = swallowed + 1;
+                resume = Some((star_end, k));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// Reads a command, either as an array of bulk strings or as an inline command
+/// of space separated words. Returns `None` if the stream ends before it starts.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|byte| *byte == b' ')
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+    let len = parse_len(&line[1..], MAX_ARRAY_LEN, "multibulk length")?;
+    // Both lengths come from the client, so buffers only grow as data arrives.
+    let mut args = Vec::with_capacity(len.min(16));
+    for _ in 0..len {
+        let header = read_line(reader)?.ok_or(KvsError::ConnectionClosedError)?;
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&header[..header.len().min(1)])
+            )));
+        }
+        let len = parse_len(&header[1..], MAX_BULK_LEN, "bulk length")?;
+        let mut arg = Vec::new();
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(KvsError::ConnectionClosedError);
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF".into()));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line terminated by CRLF, without the terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_LINE_LEN as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.len() > MAX_LINE_LEN && !line.ends_with(b"\n") {
+        return Err(protocol_error("line too long".into()));
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(protocol_error("line not terminated by CRLF".into()));
+    }
+    line.truncate(line.len() - 2);
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize, what: &str) -> Result<usize> {
+    match std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        Some(len) if len <= max => Ok(len),
+        _ => Err(protocol_error(format!("invalid {}", what))),
+    }
+}
+
+/// Answers a client the server is too busy to serve, as Redis does once it has
+/// `maxclients` clients.
+pub(crate) fn write_busy<W: Write>(writer: &mut W) -> Result<()> {
+    let reply = Reply::Error("ERR max number of clients reached".to_owned());
+    write_reply(writer, &reply)
+}
+
+fn protocol_error(message: String) -> KvsError {
+    KvsError::RespProtocolError(message)
+}
+
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> Result<()> {
+    match reply {
+        Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+        // Error messages must stay on one line.
+        Reply::Error(e) => write!(writer, "-{}\r\n", e.replace(&['\r', '\n'][..], " "))?,
+        Reply::Integer(i) => write!(writer, ":{}\r\n", i)?,
+        Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+        Reply::Bulk(Some(bytes)) => {
+            write!(writer, "${}\r\n", bytes.len())?;
+            writer.write_all(bytes)?;
+            writer.write_all(b"\r\n")?;
+        }
+        Reply::Array(items) => {
+            write!(writer, "*{}\r\n", items.len())?;
+            for item in items {
+                write_reply(writer, item)?;
+            }
+        }
+    }
+    Ok(())
+}
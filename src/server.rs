@@ -1,78 +1,555 @@
-use crate::common::{Command, Response};
+use crate::common::{
+    answer_hello, read_frame, write_frame, Command, HelloReply, KeyRange, Response,
+};
+use crate::engines::unix_millis;
 use crate::thread_pool::ThreadPool;
-use crate::KvsEngine;
 use crate::Result;
-use serde_json;
+use crate::{http, resp};
+use crate::{KvsEngine, KvsError};
 use slog::Logger;
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, prelude::*};
+use std::io::{BufReader, BufWriter};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The wire protocol a `KvsServer` speaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// The protocol of `KvsClient`.
+    Kvs,
+    /// RESP2, the Redis protocol, for use with Redis clients.
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    /// Parses `kvs` or `resp`.
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(format!("Invalid protocol {}, expected kvs or resp", s)),
+        }
+    }
+}
+
+/// What `KvsServer` does with a new connection while its pool is saturated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusyPolicy {
+    /// Waits for the pool to make room, and stops accepting connections meanwhile.
+    Block,
+    /// Tells the new client that the server is busy, and closes its connection.
+    Reject,
+    /// Turns away the client that has waited longest for a thread instead.
+    ShedOldest,
+}
+
+impl FromStr for BusyPolicy {
+    type Err = String;
+
+    /// Parses `block`, `reject` or `shed-oldest`.
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "block" => Ok(BusyPolicy::Block),
+            "reject" => Ok(BusyPolicy::Reject),
+            "shed-oldest" => Ok(BusyPolicy::ShedOldest),
+            _ => Err(format!(
+                "Invalid busy policy {}, expected block, reject or shed-oldest",
+                s
+            )),
+        }
+    }
+}
+
+/// How long a blocked server waits before offering a connection to its pool
+/// again.
+const BLOCK_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How long shutdown waits for connections to be answered by default.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a listener serves: one of the protocols, or the HTTP gateway.
+#[derive(Clone, Copy, Debug)]
+enum Frontend {
+    Protocol(Protocol),
+    Http,
+}
+
+/// What the listeners and `ShutdownHandle`s tell the thread running `start`.
+enum Event {
+    Connection(Result<TcpStream>, Frontend),
+    Shutdown,
+}
+
+/// Stops a running `KvsServer`, see `KvsServer::shutdown_handle`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stopping: Arc<AtomicBool>,
+    events: Arc<Mutex<mpsc::SyncSender<Event>>>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down. `KvsServer::start` returns once it has, or
+    /// right away if it is called later.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // This only wakes the server up, so it does not matter if it already
+        // has an event to handle.
+        let _ = self.events.lock().unwrap().try_send(Event::Shutdown);
+    }
+}
 
 pub struct KvsServer<T: KvsEngine, P: ThreadPool> {
     addr: String,
     log: Logger,
     store: T,
     pool: P,
+    protocol: Protocol,
+    http_addr: Option<String>,
+    busy_policy: BusyPolicy,
+    drain_timeout: Duration,
+    stopping: Arc<AtomicBool>,
+    events: mpsc::Receiver<Event>,
+    sender: mpsc::SyncSender<Event>,
 }
 
 impl<T: KvsEngine, P: ThreadPool> KvsServer<T, P> {
     pub fn new(addr: String, store: T, log: Logger, pool: P) -> Result<Self> {
+        // Listeners block on this channel while the server waits for its pool,
+        // so that clients wait in the listen backlog.
+        let (sender, events) = mpsc::sync_channel(1);
         Ok(KvsServer {
             addr,
             store,
             log,
             pool,
+            protocol: Protocol::Kvs,
+            http_addr: None,
+            busy_policy: BusyPolicy::Block,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            stopping: Arc::new(AtomicBool::new(false)),
+            events,
+            sender,
         })
     }
 
+    /// Returns a handle that stops the server from any thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stopping: self.stopping.clone(),
+            events: Arc::new(Mutex::new(self.sender.clone())),
+        }
+    }
+
+    /// Sets the protocol clients speak, `Protocol::Kvs` by default.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Also serves the HTTP gateway described in the `http` module on `addr`.
+    pub fn http_addr(mut self, addr: String) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Sets what to do with new connections while the pool is saturated,
+    /// `BusyPolicy::Block` by default. Only bounded pools get saturated.
+    pub fn busy_policy(mut self, policy: BusyPolicy) -> Self {
+        self.busy_policy = policy;
+        self
+    }
+
+    /// Sets how long shutdown waits for open connections to be answered before
+    /// it cuts them off, 5 seconds by default.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Serves connections until a `ShutdownHandle` stops the server. It then
+    /// stops accepting connections, lets the ones open finish the commands they
+    /// have sent, closes them and flushes the engine before returning. Clients
+    /// that are not done within the drain timeout are cut off.
     pub fn start(&mut self) -> Result<()> {
+        // Each listener accepts on its own thread, and hands the connections to
+        // this one, which owns the pool.
+        let mut listeners = Vec::new();
         let listener = TcpListener::bind(&self.addr)?;
-        for stream in listener.incoming() {
-            let stream = stream?;
-            info!(self.log, "New connection"; "client addr" => stream.peer_addr()?);
-            let log = self.log.clone();
-            let store = self.store.clone();
-            self.pool.spawn(move || {
-                if let Err(e) = handle_connection(store, &log, stream) {
-                    error!(log, "Error while handling connection: {}", e);
-                };
-            });
+        listeners.push(self.accept_on(listener, Frontend::Protocol(self.protocol))?);
+        if let Some(http_addr) = &self.http_addr {
+            let listener = TcpListener::bind(http_addr)?;
+            info!(self.log, "Serving HTTP"; "http addr" => http_addr);
+            listeners.push(self.accept_on(listener, Frontend::Http)?);
+        }
+
+        let connections = Arc::new(Connections::default());
+        let waiting = Arc::new(Mutex::new(VecDeque::new()));
+        let res = loop {
+            if self.stopping.load(Ordering::SeqCst) {
+                break Ok(());
+            }
+            match self.events.recv() {
+                Ok(Event::Connection(Ok(stream), frontend)) => {
+                    self.serve(stream, frontend, &connections, &waiting)
+                }
+                Ok(Event::Connection(Err(e), _)) => break Err(e),
+                Ok(Event::Shutdown) => {}
+                Err(_) => break Ok(()),
+            }
+        };
+
+        info!(self.log, "Shutting down");
+        self.stopping.store(true, Ordering::SeqCst);
+        for (addr, _) in &listeners {
+            // Wake the listener up, so that it sees it has to stop.
+            let _ = TcpStream::connect(wake_addr(*addr));
+        }
+        // A listener may be blocked handing over a connection it accepted
+        // before, so take those until every listener stopped.
+        while listeners
+            .iter()
+            .any(|(_, accepter)| !accepter.is_finished())
+        {
+            let _ = self.events.recv_timeout(BLOCK_RETRY_INTERVAL);
+        }
+        for (_, accepter) in listeners {
+            let _ = accepter.join();
+        }
+        // Connections still waiting for a thread are turned away.
+        drop(mem::take(&mut *waiting.lock().unwrap()));
+        connections.close_all(self.drain_timeout);
+        self.store.flush()?;
+        info!(self.log, "Server stopped");
+        res
+    }
+
+    /// Accepts connections on `listener` from a new thread until the server
+    /// stops. Returns the address it listens on and the thread.
+    fn accept_on(
+        &self,
+        listener: TcpListener,
+        frontend: Frontend,
+    ) -> Result<(SocketAddr, JoinHandle<()>)> {
+        let addr = listener.local_addr()?;
+        let sender = self.sender.clone();
+        let stopping = self.stopping.clone();
+        let accepter = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                let failed = stream.is_err();
+                let event = Event::Connection(stream.map_err(Into::into), frontend);
+                if sender.send(event).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok((addr, accepter))
+    }
+
+    /// Hands a new connection to the pool, or turns a client away if the pool
+    /// is saturated, as the busy policy says.
+    fn serve(
+        &self,
+        stream: TcpStream,
+        frontend: Frontend,
+        connections: &Arc<Connections>,
+        waiting: &Arc<Mutex<VecDeque<WaitingConnection<T>>>>,
+    ) {
+        let peer_addr = match stream.peer_addr() {
+            Ok(peer_addr) => peer_addr,
+            // The client is already gone.
+            Err(_) => return,
+        };
+        info!(self.log, "New connection"; "client addr" => peer_addr);
+        let guard = match connections.register(&stream) {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!(self.log, "Error while handling connection: {}", e);
+                return;
+            }
+        };
+        waiting.lock().unwrap().push_back(WaitingConnection {
+            stream: Some(stream),
+            frontend,
+            store: self.store.clone(),
+            log: self.log.clone(),
+            _guard: guard,
+        });
+
+        // Each job serves the connection that has waited longest rather than a
+        // given one, so that any waiting connection can be turned away.
+        loop {
+            let waiting_jobs = waiting.clone();
+            let job = move || {
+                let connection = waiting_jobs.lock().unwrap().pop_front();
+                if let Some(connection) = connection {
+                    connection.serve();
+                }
+            };
+            if self.pool.try_spawn(job).is_ok() {
+                return;
+            }
+            let turned_away = match self.busy_policy {
+                BusyPolicy::Block if !self.stopping.load(Ordering::SeqCst) => {
+                    thread::sleep(BLOCK_RETRY_INTERVAL);
+                    continue;
+                }
+                BusyPolicy::Block | BusyPolicy::Reject => waiting.lock().unwrap().pop_back(),
+                BusyPolicy::ShedOldest => waiting.lock().unwrap().pop_front(),
+            };
+            warn!(self.log, "Thread pool saturated, turning a client away");
+            drop(turned_away);
+            return;
         }
-        Ok(())
     }
 }
 
-fn handle_connection<T: KvsEngine>(store: T, log: &Logger, mut stream: TcpStream) -> Result<()> {
-    let cmd: Command = serde_json::from_reader(&stream)?;
-    let res = match cmd {
+/// A connection waiting for a thread of the pool. Dropping it instead of
+/// serving it tells the client that the server is busy.
+struct WaitingConnection<T: KvsEngine> {
+    stream: Option<TcpStream>,
+    frontend: Frontend,
+    store: T,
+    log: Logger,
+    _guard: ConnectionGuard,
+}
+
+impl<T: KvsEngine> WaitingConnection<T> {
+    fn serve(mut self) {
+        let stream = self.stream.take().expect("connection served twice");
+        let (store, log) = (self.store.clone(), &self.log);
+        let res = match self.frontend {
+            Frontend::Protocol(Protocol::Kvs) => handle_connection(store, log, stream),
+            Frontend::Protocol(Protocol::Resp) => resp::handle_connection(store, log, stream),
+            Frontend::Http => http::handle_connection(store, log, stream),
+        };
+        if let Err(e) = res {
+            error!(log, "Error while handling connection: {}", e);
+        };
+    }
+}
+
+impl<T: KvsEngine> Drop for WaitingConnection<T> {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            if let Err(e) = turn_away(stream, self.frontend) {
+                debug!(self.log, "Error while turning a client away: {}", e);
+            }
+        }
+    }
+}
+
+/// Tells a client that the server is too busy to serve it, and closes the
+/// connection.
+fn turn_away(mut stream: TcpStream, frontend: Frontend) -> Result<()> {
+    match frontend {
+        Frontend::Protocol(Protocol::Kvs) => write_frame(&mut stream, &HelloReply::Busy)?,
+        Frontend::Protocol(Protocol::Resp) => resp::write_busy(&mut stream)?,
+        Frontend::Http => http::write_busy(&mut stream)?,
+    }
+    stream.shutdown(Shutdown::Write)?;
+    // Closing a connection with unread data resets it, which can lose the
+    // reply, so read what the client has sent so far.
+    stream.set_nonblocking(true)?;
+    let mut buf = [0; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(KvsError::IOError(e)),
+        }
+    }
+}
+
+/// Returns an address that connects to a listener bound to `addr`.
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// The connections being served, so that shutdown can close them and wait for
+/// their handlers to finish.
+#[derive(Default)]
+struct Connections {
+    open: Mutex<(u64, HashMap<u64, TcpStream>)>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn register(self: &Arc<Self>, stream: &TcpStream) -> Result<ConnectionGuard> {
+        let mut open = self.open.lock().unwrap();
+        let id = open.0;
+        open.0 += 1;
+        open.1.insert(id, stream.try_clone()?);
+        Ok(ConnectionGuard {
+            connections: self.clone(),
+            id,
+        })
+    }
+
+    /// Stops reading from every connection, so that handlers finish once they
+    /// have answered the commands already received, and waits for them. After
+    /// `timeout`, the connections left are closed altogether.
+    fn close_all(&self, timeout: Duration) {
+        let mut open = self.open.lock().unwrap();
+        for stream in open.1.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        open = self
+            .closed
+            .wait_timeout_while(open, timeout, |open| !open.1.is_empty())
+            .unwrap()
+            .0;
+        // A handler blocked writing to a client that stopped reading fails
+        // as soon as its connection is shut down.
+        for stream in open.1.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        while !open.1.is_empty() {
+            open = self.closed.wait(open).unwrap();
+        }
+    }
+}
+
+/// Unregisters a connection when its handler is done with it, or is dropped
+/// without running.
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.1.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+/// Serves the commands sent on a connection, in order, until the client closes it.
+/// Clients may pipeline commands: responses are only flushed once every command
+/// already received has been answered.
+fn handle_connection<T: KvsEngine>(store: T, log: &Logger, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let reply = match answer_hello(read_frame(&mut reader))? {
+        Some(reply) => reply,
+        None => return Ok(()),
+    };
+    write_frame(&mut writer, &reply)?;
+    writer.flush()?;
+    reply.features()?;
+    while let Some(cmd) = read_frame(&mut reader)? {
+        let res = handle_command(&store, log, cmd);
+        write_frame(&mut writer, &res)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    debug!(log, "Connection closed by client");
+    Ok(())
+}
+
+pub(crate) fn handle_command<T: KvsEngine>(store: &T, log: &Logger, cmd: Command) -> Response {
+    match cmd {
         Command::Get(key) => {
-            debug!(log, "Received get command, key: {}", key);
-            match store.get(key) {
+            debug!(
+                log,
+                "Received get command, key: {}",
+                String::from_utf8_lossy(&key)
+            );
+            match store.get_bytes(key) {
                 Ok(value) => Response::Ok(value),
-                Err(e) => Response::Err(e.to_string()),
+                Err(e) => Response::error(e),
             }
         }
         Command::Set(key, value) => {
             debug!(
                 log,
-                "Received Set command with key: {}, value: {}", key, value
+                "Received Set command with key: {}, value length: {}",
+                String::from_utf8_lossy(&key),
+                value.len()
             );
-            match store.set(key, value) {
+            match store.set_bytes(key, value) {
                 Ok(_) => Response::Ok(None),
-                Err(e) => Response::Err(e.to_string()),
+                Err(e) => Response::error(e),
+            }
+        }
+        Command::SetExpiring(key, value, expires_at) => {
+            debug!(
+                log,
+                "Received SetExpiring command with key: {}, value length: {}, deadline: {}",
+                String::from_utf8_lossy(&key),
+                value.len(),
+                expires_at
+            );
+            let ttl = Duration::from_millis(expires_at.saturating_sub(unix_millis()));
+            match store.set_bytes_with_ttl(key, value, ttl) {
+                Ok(_) => Response::Ok(None),
+                Err(e) => Response::error(e),
             }
         }
         Command::Rm(key) => {
-            debug!(log, "Received Rm command key: {}", key);
-            match store.remove(key) {
+            debug!(
+                log,
+                "Received Rm command key: {}",
+                String::from_utf8_lossy(&key)
+            );
+            match store.remove_bytes(key) {
                 Ok(_) => Response::Ok(None),
                 Err(e) => {
                     error!(log, "{}", e);
-                    Response::Err(e.to_string())
+                    Response::error(e)
                 }
             }
         }
-    };
-    serde_json::to_writer(&mut stream, &res)?;
-    stream.flush()?;
-    Ok(())
+        Command::Batch(batch) => {
+            debug!(log, "Received Batch command with {} writes", batch.len());
+            match store.write_batch(batch) {
+                Ok(_) => Response::Ok(None),
+                Err(e) => Response::error(e),
+            }
+        }
+        Command::Cas(key, expected, new) => {
+            debug!(
+                log,
+                "Received Cas command, key: {}",
+                String::from_utf8_lossy(&key)
+            );
+            match store.compare_and_swap_bytes(key, expected, new) {
+                Ok(outcome) => Response::Cas(outcome),
+                Err(e) => Response::error(e),
+            }
+        }
+        Command::Scan(range, options) => {
+            debug!(log, "Received Scan command, range: {:?}", range);
+            let pairs = match range {
+                KeyRange::Range(start, end) => {
+                    let start = start.map_or(Bound::Unbounded, Bound::Included);
+                    let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+                    store.scan_bytes((start, end), options)
+                }
+                KeyRange::Prefix(prefix) => store.scan_prefix_bytes(prefix, options),
+            };
+            match pairs {
+                Ok(pairs) => Response::Pairs(pairs.collect()),
+                Err(e) => Response::error(e),
+            }
+        }
+    }
 }
@@ -0,0 +1,33 @@
+use super::ThreadPool;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+
+/// Waits for a job spawned with `ThreadPool::spawn_with_handle`.
+pub struct JobHandle<T> {
+    result: mpsc::Receiver<thread::Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the job to finish, and returns what it returned, or what it
+    /// panicked with, like `std::thread::JoinHandle::join`.
+    pub fn join(self) -> thread::Result<T> {
+        self.result
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new("job was dropped before it ran")))
+    }
+}
+
+pub(super) fn spawn_with_handle<P, F, T>(pool: &P, job: F) -> JobHandle<T>
+where
+    P: ThreadPool,
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(1);
+    pool.spawn(move || {
+        // The panic is handed to the handle, so the pool does not see it.
+        let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(job)));
+    });
+    JobHandle { result: receiver }
+}
@@ -1,18 +1,178 @@
-use crate::Result;
+use crate::{KvsError, Result};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
 pub trait ThreadPool {
     fn new(size: usize) -> Result<Self>
+    where
+        Self: std::marker::Sized;
+    /// Creates a pool of `size` threads where at most `queue_len` jobs wait for
+    /// a thread. `spawn` then blocks while the pool is saturated.
+    fn bounded(size: usize, queue_len: usize) -> Result<Self>
     where
         Self: std::marker::Sized;
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+    /// Runs `job` like `spawn`, unless the pool is saturated. The job is then
+    /// dropped, and `KvsError::PoolSaturatedError` returned.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static;
+    /// Stops the pool once the jobs spawned so far have run, and waits at most
+    /// `timeout` for its threads to exit. Fails with
+    /// `KvsError::PoolShutdownTimeoutError` if some are still running then.
+    ///
+    /// Dropping the pool does the same, with a timeout of a few seconds.
+    fn shutdown(self, timeout: Duration) -> Result<()>
+    where
+        Self: std::marker::Sized;
+    /// Stops the pool like `shutdown`, but drops the jobs that have not
+    /// started yet instead of running them.
+    fn shutdown_now(self, timeout: Duration) -> Result<()>
+    where
+        Self: std::marker::Sized;
+
+    /// Runs `job` like `spawn`, and returns a handle to wait for its result.
+    /// A panic of the job is caught, and returned by `JobHandle::join`.
+    fn spawn_with_handle<F, T>(&self, job: F) -> JobHandle<T>
+    where
+        Self: std::marker::Sized,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        handle::spawn_with_handle(self, job)
+    }
+
+    /// Calls `f` with a `Scope` whose jobs may borrow from the caller's stack,
+    /// and returns once they are all done. If `f` or a job panicked, `scope`
+    /// then panics with the first payload.
+    ///
+    /// It must not be called from a job of the same pool, whose threads could
+    /// then all wait for jobs that have no thread left to run on.
+    fn scope<'env, F, R>(&'env self, f: F) -> R
+    where
+        Self: std::marker::Sized,
+        F: FnOnce(&Scope<'env, Self>) -> R,
+    {
+        scope::scope(self, f)
+    }
 }
 
+mod handle;
 mod naive_threadpool;
 mod rayon_threadpool;
+mod scope;
 mod shared_queue;
 
+pub use self::handle::JobHandle;
 pub use self::naive_threadpool::NaiveThreadPool;
 pub use self::rayon_threadpool::RayonThreadPool;
+pub use self::scope::Scope;
 pub use self::shared_queue::SharedQueueThreadPool;
+
+/// How long dropping a pool waits for its threads to exit.
+const DROP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counts the threads of a pool that have not exited, so that stopping the
+/// pool can wait for them.
+#[derive(Default)]
+struct Threads {
+    running: Mutex<usize>,
+    exited: Condvar,
+}
+
+/// Stands for a running thread. Dropping it, last thing on the thread, tells
+/// that the thread exited.
+struct ThreadGuard(Arc<Threads>);
+
+impl Threads {
+    fn started(&self) {
+        *self.running.lock().unwrap() += 1;
+    }
+
+    fn exited(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.exited.notify_all();
+    }
+
+    /// Counts a thread about to be spawned, which must then hold the guard.
+    fn start(self: &Arc<Self>) -> ThreadGuard {
+        self.started();
+        ThreadGuard(self.clone())
+    }
+
+    /// Waits at most `timeout` for all the threads to exit.
+    fn wait(&self, timeout: Duration) -> Result<()> {
+        let running = self.running.lock().unwrap();
+        let (running, _) = self
+            .exited
+            .wait_timeout_while(running, timeout, |running| *running > 0)
+            .unwrap();
+        match *running {
+            0 => Ok(()),
+            running => Err(KvsError::PoolShutdownTimeoutError(running)),
+        }
+    }
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        self.0.exited();
+    }
+}
+
+/// Counts the jobs of a bounded pool that have not finished, for pools that
+/// cannot tell how many jobs they hold.
+struct JobLimit {
+    max: usize,
+    unfinished: Mutex<usize>,
+    finished: Condvar,
+}
+
+/// Stands for one unfinished job. Dropping it, once the job ran or panicked,
+/// makes room for another.
+struct JobPermit(Arc<JobLimit>);
+
+impl JobLimit {
+    fn new(max: usize) -> Arc<Self> {
+        Arc::new(JobLimit {
+            max,
+            unfinished: Mutex::new(0),
+            finished: Condvar::new(),
+        })
+    }
+
+    /// Waits until there is room for a job.
+    fn acquire(self: &Arc<Self>) -> JobPermit {
+        let mut unfinished = self.unfinished.lock().unwrap();
+        while *unfinished >= self.max {
+            unfinished = self.finished.wait(unfinished).unwrap();
+        }
+        *unfinished += 1;
+        JobPermit(self.clone())
+    }
+
+    fn try_acquire(self: &Arc<Self>) -> Result<JobPermit> {
+        let mut unfinished = self.unfinished.lock().unwrap();
+        if *unfinished >= self.max {
+            return Err(KvsError::PoolSaturatedError);
+        }
+        *unfinished += 1;
+        Ok(JobPermit(self.clone()))
+    }
+}
+
+impl Drop for JobPermit {
+    fn drop(&mut self) {
+        *self.0.unfinished.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+fn check_size(size: usize) -> Result<()> {
+    if size == 0 {
+        return Err(KvsError::Err("Thread pool size must not be zero".into()));
+    }
+    Ok(())
+}
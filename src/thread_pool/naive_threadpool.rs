@@ -1,17 +1,88 @@
-use super::ThreadPool;
+use super::{JobLimit, JobPermit, ThreadGuard, ThreadPool, Threads};
 use crate::Result;
+use std::sync::Arc;
 use std::thread;
-pub struct NaiveThreadPool;
+use std::time::Duration;
+
+/// Runs each job on a new thread. A bounded pool runs at most `size` plus
+/// `queue_len` jobs at once, as nothing waits for a thread here.
+pub struct NaiveThreadPool {
+    limit: Option<Arc<JobLimit>>,
+    threads: Arc<Threads>,
+    stopped: bool,
+}
+
+impl NaiveThreadPool {
+    fn with_limit(limit: Option<Arc<JobLimit>>) -> NaiveThreadPool {
+        NaiveThreadPool {
+            limit,
+            threads: Arc::default(),
+            stopped: false,
+        }
+    }
+
+    /// Waits for the running jobs. No job ever waits for a thread, so there
+    /// is nothing to cancel.
+    fn stop(&mut self, timeout: Duration) -> Result<()> {
+        self.stopped = true;
+        self.threads.wait(timeout)
+    }
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(size: usize) -> Result<NaiveThreadPool> {
-        Ok(NaiveThreadPool)
+    fn new(_size: usize) -> Result<NaiveThreadPool> {
+        Ok(Self::with_limit(None))
+    }
+
+    fn bounded(size: usize, queue_len: usize) -> Result<NaiveThreadPool> {
+        super::check_size(size)?;
+        Ok(Self::with_limit(Some(JobLimit::new(size + queue_len))))
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        let permit = self.limit.as_ref().map(|limit| limit.acquire());
+        run(job, permit, self.threads.start());
     }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let permit = match &self.limit {
+            Some(limit) => Some(limit.try_acquire()?),
+            None => None,
+        };
+        run(job, permit, self.threads.start());
+        Ok(())
+    }
+
+    fn shutdown(mut self, timeout: Duration) -> Result<()> {
+        self.stop(timeout)
+    }
+
+    fn shutdown_now(mut self, timeout: Duration) -> Result<()> {
+        self.stop(timeout)
+    }
+}
+
+impl Drop for NaiveThreadPool {
+    fn drop(&mut self) {
+        if !self.stopped {
+            let _ = self.stop(super::DROP_TIMEOUT);
+        }
+    }
+}
+
+fn run<F>(job: F, permit: Option<JobPermit>, thread: ThreadGuard)
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::spawn(move || {
+        let _thread = thread;
+        let _permit = permit;
+        job()
+    });
 }
@@ -1,15 +1,114 @@
-use super::ThreadPool;
-use crate::Result;
+use super::{JobLimit, JobPermit, ThreadPool, Threads};
+use crate::{KvsError, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct RayonThreadPool;
+/// A work-stealing pool of a fixed number of threads, backed by rayon.
+pub struct RayonThreadPool {
+    // Only taken when the pool stops. Rayon then lets its threads exit once
+    // the jobs spawned so far have run.
+    pool: Option<rayon::ThreadPool>,
+    // Rayon does not tell how many jobs wait, so a bounded pool counts them.
+    limit: Option<Arc<JobLimit>>,
+    threads: Arc<Threads>,
+    // Rayon cannot take back a job, so a cancelled one is dropped when it
+    // would start instead.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RayonThreadPool {
+    fn build(size: usize, limit: Option<Arc<JobLimit>>) -> Result<RayonThreadPool> {
+        // Rayon picks the size itself when asked for zero threads.
+        super::check_size(size)?;
+        let threads = Arc::<Threads>::default();
+        for _ in 0..size {
+            threads.started();
+        }
+        let exiting = threads.clone();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(size)
+            .thread_name(|i| format!("kvs-rayon-{}", i))
+            // Without a handler, rayon aborts the process when a job panics.
+            .panic_handler(|_| {})
+            .exit_handler(move |_| exiting.exited())
+            .build()
+            .map_err(|e| KvsError::Err(e.to_string()))?;
+        Ok(RayonThreadPool {
+            pool: Some(pool),
+            limit,
+            threads,
+            cancelled: Arc::default(),
+        })
+    }
+
+    fn run<F>(&self, job: F, permit: Option<JobPermit>)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let cancelled = self.cancelled.clone();
+        let pool = self.pool.as_ref().expect("thread pool is stopped");
+        pool.spawn(move || {
+            let _permit = permit;
+            if !cancelled.load(Ordering::SeqCst) {
+                job()
+            }
+        });
+    }
+
+    /// Lets the threads exit once the jobs spawned so far are done, or
+    /// dropped if `cancel` is set, and waits for them.
+    fn stop(&mut self, cancel: bool, timeout: Duration) -> Result<()> {
+        if cancel {
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+        self.pool = None;
+        self.threads.wait(timeout)
+    }
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(size: usize) -> Result<RayonThreadPool> {
-        Ok(RayonThreadPool)
+        Self::build(size, None)
+    }
+
+    fn bounded(size: usize, queue_len: usize) -> Result<RayonThreadPool> {
+        Self::build(size, Some(JobLimit::new(size + queue_len)))
     }
+
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
+        let permit = self.limit.as_ref().map(|limit| limit.acquire());
+        self.run(job, permit);
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let permit = match &self.limit {
+            Some(limit) => Some(limit.try_acquire()?),
+            None => None,
+        };
+        self.run(job, permit);
+        Ok(())
+    }
+
+    fn shutdown(mut self, timeout: Duration) -> Result<()> {
+        self.stop(false, timeout)
+    }
+
+    fn shutdown_now(mut self, timeout: Duration) -> Result<()> {
+        self.stop(true, timeout)
+    }
+}
+
+impl Drop for RayonThreadPool {
+    fn drop(&mut self) {
+        if self.pool.is_some() {
+            let _ = self.stop(false, super::DROP_TIMEOUT);
+        }
     }
 }
@@ -0,0 +1,94 @@
+use super::ThreadPool;
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Spawns jobs that may borrow anything that outlives `'env`, see
+/// `ThreadPool::scope`.
+pub struct Scope<'env, P> {
+    pool: &'env P,
+    state: Arc<ScopeState>,
+    // Makes `Scope` invariant in `'env`, so that it cannot be shortened to let
+    // jobs borrow data that does not outlive the call to `ThreadPool::scope`.
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+#[derive(Default)]
+struct ScopeState {
+    unfinished: Mutex<usize>,
+    finished: Condvar,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+/// A job of a scope. Its fields drop in order, so that the scope only learns
+/// the job is done once everything it borrowed is dropped, even if the pool
+/// drops it without running it.
+struct ScopedJob<F> {
+    job: F,
+    _unfinished: Unfinished,
+}
+
+struct Unfinished(Arc<ScopeState>);
+
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        *self.0.unfinished.lock().unwrap() -= 1;
+        self.0.finished.notify_all();
+    }
+}
+
+impl<'env, P: ThreadPool> Scope<'env, P> {
+    /// Runs `job` on the pool. It is done by the time `ThreadPool::scope`
+    /// returns.
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'env,
+    {
+        *self.state.unfinished.lock().unwrap() += 1;
+        let scoped = ScopedJob {
+            job,
+            _unfinished: Unfinished(self.state.clone()),
+        };
+        let state = self.state.clone();
+        let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+            let ScopedJob { job, _unfinished } = scoped;
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                state.panic.lock().unwrap().get_or_insert(payload);
+            }
+        });
+        // SAFETY: `scope` does not return before every job is dropped, run or
+        // not, so the job never outlives what it borrows for `'env`.
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+        self.pool.spawn(job);
+    }
+
+    fn wait(&self) {
+        let mut unfinished = self.state.unfinished.lock().unwrap();
+        while *unfinished > 0 {
+            unfinished = self.state.finished.wait(unfinished).unwrap();
+        }
+    }
+}
+
+pub(super) fn scope<'env, P, F, R>(pool: &'env P, f: F) -> R
+where
+    P: ThreadPool,
+    F: FnOnce(&Scope<'env, P>) -> R,
+{
+    let scope = Scope {
+        pool,
+        state: Arc::new(ScopeState::default()),
+        _env: PhantomData,
+    };
+    // The jobs must be waited for even if `f` panics, as they may borrow from
+    // the stack being unwound.
+    let res = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    scope.wait();
+    let job_panic = scope.state.panic.lock().unwrap().take();
+    match (res, job_panic) {
+        (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+        (Ok(res), None) => res,
+    }
+}
@@ -1,15 +1,142 @@
-use super::ThreadPool;
-use crate::Result;
+use super::{ThreadPool, Threads};
+use crate::{KvsError, Result};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use std::io;
+use std::sync::Arc;
 use std::thread;
-pub struct SharedQueueThreadPool;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads taking jobs from a shared queue. A thread whose job
+/// panics is replaced, so the pool never shrinks. The threads exit once the
+/// pool is stopped and the queue is empty.
+pub struct SharedQueueThreadPool {
+    // Only taken when the pool stops, which closes the queue.
+    jobs: Option<Sender<Job>>,
+    queue: Receiver<Job>,
+    threads: Arc<Threads>,
+}
+
+impl SharedQueueThreadPool {
+    fn start(size: usize, jobs: Sender<Job>, queue: Receiver<Job>) -> Result<Self> {
+        super::check_size(size)?;
+        let pool = SharedQueueThreadPool {
+            jobs: Some(jobs),
+            queue,
+            threads: Arc::default(),
+        };
+        for _ in 0..size {
+            spawn_worker(Worker {
+                jobs: pool.queue.clone(),
+                threads: pool.threads.clone(),
+            })?;
+        }
+        Ok(pool)
+    }
+
+    fn sender(&self) -> &Sender<Job> {
+        self.jobs.as_ref().expect("thread pool is stopped")
+    }
+
+    /// Closes the queue, dropping the jobs still in it if `cancel` is set, and
+    /// waits for the workers to exit.
+    fn stop(&mut self, cancel: bool, timeout: Duration) -> Result<()> {
+        self.jobs = None;
+        if cancel {
+            self.queue.try_iter().for_each(drop);
+        }
+        self.threads.wait(timeout)
+    }
+}
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(size: usize) -> Result<SharedQueueThreadPool> {
-        Ok(SharedQueueThreadPool)
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Self::start(size, sender, receiver)
+    }
+
+    fn bounded(size: usize, queue_len: usize) -> Result<SharedQueueThreadPool> {
+        let (sender, receiver) = crossbeam_channel::bounded(queue_len);
+        Self::start(size, sender, receiver)
     }
+
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
+        // Workers only stop once the pool is stopped, so the queue is open.
+        self.sender()
+            .send(Box::new(job))
+            .expect("thread pool has no workers");
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender().try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(KvsError::PoolSaturatedError),
+            Err(TrySendError::Disconnected(_)) => panic!("thread pool has no workers"),
+        }
     }
+
+    fn shutdown(mut self, timeout: Duration) -> Result<()> {
+        self.stop(false, timeout)
+    }
+
+    fn shutdown_now(mut self, timeout: Duration) -> Result<()> {
+        self.stop(true, timeout)
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        if self.jobs.is_some() {
+            let _ = self.stop(false, super::DROP_TIMEOUT);
+        }
+    }
+}
+
+/// Runs jobs from the queue on its own thread.
+struct Worker {
+    jobs: Receiver<Job>,
+    threads: Arc<Threads>,
+}
+
+impl Worker {
+    fn run(self) {
+        for job in self.jobs.iter() {
+            job();
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // The worker is only dropped while its thread panics if a job panicked.
+        if thread::panicking() {
+            let worker = Worker {
+                jobs: self.jobs.clone(),
+                threads: self.threads.clone(),
+            };
+            // There is no one to report a failure to, and the other workers
+            // still run the jobs.
+            let _ = spawn_worker(worker);
+        }
+    }
+}
+
+fn spawn_worker(worker: Worker) -> io::Result<()> {
+    let thread = worker.threads.start();
+    thread::Builder::new()
+        .name("kvs-worker".into())
+        .spawn(move || {
+            // Declared first to be dropped last, after a replacement worker
+            // got counted.
+            let _thread = thread;
+            worker.run()
+        })?;
+    Ok(())
 }
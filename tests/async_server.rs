@@ -0,0 +1,87 @@
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, KvStore, KvsClient, KvsServer, NaiveThreadPool, Result,
+    ScanOptions, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Connects once the server has started listening.
+async fn connect(addr: &str) -> AsyncKvsClient {
+    for _ in 0..100 {
+        if let Ok(client) = AsyncKvsClient::connect(addr).await {
+            return client;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server at {} did not start", addr);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_and_server() -> Result<()> {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let server = AsyncKvsServer::new(addr.to_owned(), store, Logger::root(Discard, o!()));
+    tokio::spawn(server.run());
+
+    let mut client = connect(addr).await;
+    client.set("key1", "value1").await?;
+    client.set_bytes(b"key2", &[0xff, 0x00]).await?;
+    assert_eq!(client.get("key1").await?, Some("value1".to_owned()));
+    assert_eq!(client.get_bytes(b"key2").await?, Some(vec![0xff, 0x00]));
+    assert_eq!(client.get("missing").await?, None);
+    assert!(client.remove("missing").await.is_err());
+    let outcome = client
+        .compare_and_swap("key1", Some("value1"), Some("value2"))
+        .await?;
+    assert!(outcome.succeeded);
+    let pairs = client.scan_prefix("key1", ScanOptions::new()).await?;
+    assert_eq!(pairs, vec![("key1".to_owned(), "value2".to_owned())]);
+    client.remove("key1").await?;
+    assert_eq!(client.get("key1").await?, None);
+
+    // Idle connections should not hold up the others.
+    let mut idle = Vec::new();
+    for _ in 0..500 {
+        idle.push(connect(addr).await);
+    }
+    client.set("key3", "value3").await?;
+    for client in idle.iter_mut().step_by(50) {
+        assert_eq!(client.get("key3").await?, Some("value3".to_owned()));
+    }
+
+    // The blocking client speaks the same protocol.
+    let value = tokio::task::spawn_blocking(move || -> Result<Option<String>> {
+        KvsClient::connect(addr)?.get("key3")
+    })
+    .await
+    .unwrap()?;
+    assert_eq!(value, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_with_blocking_server() -> Result<()> {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = NaiveThreadPool::new(4)?;
+    let mut server = KvsServer::new(addr.to_owned(), store, Logger::root(Discard, o!()), pool)?;
+    thread::spawn(move || server.start());
+
+    let mut client = connect(addr).await;
+    for i in 0..100 {
+        client
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .await?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(&format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
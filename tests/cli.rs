@@ -1,6 +1,10 @@
 use assert_cmd::prelude::*;
+use kvs::KvsClient;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -78,6 +82,21 @@ fn client_cli_invalid_set() {
         .assert()
         .failure();
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key", "value", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key", "value", "--ttl", "9999999999999999h"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("too long"));
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["get", "key", "--unknown-flag"])
@@ -151,6 +170,28 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_sync_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--sync", "sometimes", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid sync policy"));
+}
+
+#[test]
+fn server_cli_invalid_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--protocol", "http", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid protocol"));
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +265,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap(); // the engine stays locked until the server exits
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +347,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap(); // the engine stays locked until the server exits
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +378,475 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_scan() {
+    let addr = "127.0.0.1:4026";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in &[("key2", "value3"), ("key3", "value4"), ("other", "value5")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "key", "--reverse", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key3 value4\nkey2 value3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key0", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2 value3\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Binary keys and values should round-trip byte for byte.
+#[test]
+fn client_binary_values() {
+    let addr = "127.0.0.1:4027";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let value = vec![0xff, 0x00, b'"', b'\\', 0x80];
+    client.set_bytes(&[0xfe, 0x01], &value).unwrap();
+    assert_eq!(client.get_bytes(&[0xfe, 0x01]).unwrap(), Some(value));
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A single connection should carry any number of commands.
+#[test]
+fn client_reuses_connection() {
+    let addr = "127.0.0.1:4028";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..100 {
+        client
+            .set(&format!("conn{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(&format!("conn{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    assert!(client.remove("missing").is_err());
+    assert_eq!(client.get("conn0").unwrap(), Some("value0".to_owned()));
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Pipelines bigger than the socket buffers should not stall either side.
+#[test]
+fn client_pipeline() {
+    let addr = "127.0.0.1:4029";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let value = "x".repeat(1000);
+    let mut pipeline = client.pipeline();
+    for i in 0..2000 {
+        pipeline.set(&format!("bulk{}", i), &value);
+    }
+    pipeline.remove("missing");
+    let results = pipeline.execute().unwrap();
+    assert_eq!(results.len(), 2001);
+    assert!(results[..2000].iter().all(|res| res.is_ok()));
+    assert!(results[2000].is_err());
+    let mut pipeline = client.pipeline();
+    for i in 0..2000 {
+        pipeline.get(&format!("bulk{}", i));
+    }
+    pipeline.get("missing");
+    let results = pipeline.execute().unwrap();
+    assert!(results[..2000]
+        .iter()
+        .all(|res| res.as_ref().unwrap().as_ref() == Some(&value.as_bytes().to_vec())));
+    assert_eq!(results[2000].as_ref().unwrap(), &None);
+    assert!(client.pipeline().execute().unwrap().is_empty());
+    assert_eq!(client.get("bulk0").unwrap(), Some(value));
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Sends `request` and checks that the server replies with exactly `reply`.
+fn check_resp(stream: &mut TcpStream, request: &str, reply: &str) {
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut buf = vec![0; reply.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&buf),
+        reply,
+        "reply to {:?}",
+        request
+    );
+}
+
+#[test]
+fn cli_resp_protocol() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--protocol", "resp", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    check_resp(&mut stream, "*1\r\n$4\r\nPING\r\n", "+PONG\r\n");
+    check_resp(&mut stream, "PING hello\r\n", "$5\r\nhello\r\n");
+    check_resp(
+        &mut stream,
+        "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nva\r\nue\r\n",
+        "+OK\r\n",
+    );
+    check_resp(
+        &mut stream,
+        "*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        "$6\r\nva\r\nue\r\n",
+    );
+    check_resp(&mut stream, "GET missing\r\n", "$-1\r\n");
+    // Pipelined commands are answered in order.
+    check_resp(
+        &mut stream,
+        "SET key2 value2\r\nSET key3 value3 EX 100\r\nEXISTS key1 key2 missing\r\n",
+        "+OK\r\n+OK\r\n:2\r\n",
+    );
+    check_resp(
+        &mut stream,
+        "SCAN 0 COUNT 2\r\n",
+        "*2\r\n$8\r\n6b657933\r\n*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+    );
+    check_resp(
+        &mut stream,
+        "SCAN 6b657933 COUNT 2\r\n",
+        "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey3\r\n",
+    );
+    check_resp(
+        &mut stream,
+        "SCAN 0 MATCH *2\r\n",
+        "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey2\r\n",
+    );
+    // Patterns with many stars are matched without backtracking over every split.
+    let long_key = "a".repeat(200);
+    check_resp(&mut stream, &format!("SET {} 1\r\n", long_key), "+OK\r\n");
+    check_resp(
+        &mut stream,
+        "SCAN 0 MATCH *a*a*a*a*a*a*a*a*b\r\n",
+        "*2\r\n$1\r\n0\r\n*0\r\n",
+    );
+    check_resp(
+        &mut stream,
+        "SCAN 0 MATCH *a?a\\a\r\n",
+        &format!("*2\r\n$1\r\n0\r\n*1\r\n$200\r\n{}\r\n", long_key),
+    );
+    check_resp(&mut stream, "EXPIRE key1 100\r\n", ":1\r\n");
+    check_resp(&mut stream, "EXPIRE missing 100\r\n", ":0\r\n");
+    check_resp(&mut stream, "EXPIRE key2 0\r\n", ":1\r\n");
+    check_resp(&mut stream, "DEL key1 key2 missing\r\n", ":1\r\n");
+    check_resp(&mut stream, "GET key1\r\n", "$-1\r\n");
+    check_resp(
+        &mut stream,
+        "SET key1 value1 EX soon\r\n",
+        "-ERR invalid expire time in 'set' command\r\n",
+    );
+    check_resp(
+        &mut stream,
+        "GET\r\n",
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    );
+    check_resp(
+        &mut stream,
+        "FLUSHALL\r\n",
+        "-ERR unknown command 'flushall'\r\n",
+    );
+
+    stream.write_all(b"INFO\r\n").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut header = String::new();
+    reader.read_line(&mut header).unwrap();
+    let mut info = vec![0; header[1..].trim_end().parse::<usize>().unwrap() + 2];
+    reader.read_exact(&mut info).unwrap();
+    assert!(String::from_utf8_lossy(&info).contains(env!("CARGO_PKG_VERSION")));
+
+    check_resp(&mut stream, "QUIT\r\n", "+OK\r\n");
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    // Malformed requests are reported before the connection is closed.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    check_resp(
+        &mut stream,
+        "*1\r\n+PING\r\n",
+        "-ERR Protocol error: expected '$', got '+'\r\n",
+    );
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    check_resp(
+        &mut stream,
+        &"a".repeat(64 * 1024 + 2),
+        "-ERR Protocol error: line too long\r\n",
+    );
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Sends a request on a new connection and returns the whole response.
+fn http_request(addr: &str, method: &str, target: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    res
+}
+
+#[test]
+fn cli_http_gateway() {
+    let (addr, http_addr) = ("127.0.0.1:4009", "127.0.0.1:4010");
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr, "--http-addr", http_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let res = http_request(http_addr, "PUT", "/keys/key1", "value1");
+    assert!(res.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", res);
+    let res = http_request(http_addr, "PUT", "/keys/key%202", "value 2");
+    assert!(res.starts_with("HTTP/1.1 204"), "{}", res);
+    let res = http_request(http_addr, "GET", "/keys/key1", "");
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+    assert!(res.ends_with("\r\n\r\nvalue1"), "{}", res);
+
+    // The gateway shares the store with the other protocol.
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key 2").unwrap(), Some("value 2".to_owned()));
+    client.set("other", "value3").unwrap();
+
+    let res = http_request(http_addr, "GET", "/keys?prefix=key", "");
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+    assert!(
+        res.contains("Content-Type: application/json\r\n"),
+        "{}",
+        res
+    );
+    assert!(
+        res.ends_with(r#"[{"key":"key 2","value":"value 2"},{"key":"key1","value":"value1"}]"#),
+        "{}",
+        res
+    );
+    let res = http_request(http_addr, "GET", "/keys?limit=1", "");
+    assert!(
+        res.ends_with(r#"[{"key":"key 2","value":"value 2"}]"#),
+        "{}",
+        res
+    );
+
+    let res = http_request(http_addr, "DELETE", "/keys/key1", "");
+    assert!(res.starts_with("HTTP/1.1 204"), "{}", res);
+    let res = http_request(http_addr, "GET", "/keys/key1", "");
+    assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", res);
+    let res = http_request(http_addr, "DELETE", "/keys/key1", "");
+    assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", res);
+    let res = http_request(http_addr, "POST", "/keys/key1", "");
+    assert!(res.starts_with("HTTP/1.1 405"), "{}", res);
+    assert!(res.contains("Allow: GET, PUT, DELETE\r\n"), "{}", res);
+    let res = http_request(http_addr, "GET", "/other", "");
+    assert!(res.starts_with("HTTP/1.1 404"), "{}", res);
+
+    // Requests can share a connection.
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    stream
+        .write_all(b"GET /keys/other HTTP/1.1\r\n\r\nGET /keys/other HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    assert_eq!(res.matches("HTTP/1.1 200 OK").count(), 2, "{}", res);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_async_server() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--async", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--async", "--protocol", "resp", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_server_stops_on_sigterm() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1", "value1").unwrap();
+    // The open connection must not keep the server from stopping.
+    let status = Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+    assert!(client.get("key1").is_err());
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_thread_pools() {
+    let addr = "127.0.0.1:4021";
+    for pool in &["shared", "rayon"] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(&["--pool", pool, "--threads", "2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--pool", "shared", "--threads", "0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
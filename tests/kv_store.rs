@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CasOutcome, KvStore, KvStoreOptions, KvsEngine, KvsError, Result, ScanOptions, SledStore,
+    SyncPolicy, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -127,6 +134,392 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Writes racing with background compactions must never be lost.
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..500 {
+                for key_id in 0..10 {
+                    store
+                        .set(
+                            format!("key{}-{}", thread_id, key_id),
+                            format!("value{}", iter),
+                        )
+                        .unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..10 {
+                assert_eq!(
+                    store.get(format!("key{}-{}", thread_id, key_id))?,
+                    Some("value499".to_owned())
+                );
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// The active log should roll over to a new file once it reaches the configured size.
+#[test]
+fn max_file_size_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(256)
+        .sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let files = log_files(temp_dir.path());
+    assert!(
+        files.len() > 5,
+        "expected rollovers, got {} files",
+        files.len()
+    );
+    for file in &files {
+        assert!(fs::metadata(file)?.len() < 256 + 64);
+    }
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn interval_sync_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .compaction_ratio(0.5)
+        .sync_policy(SyncPolicy::Interval(Duration::from_millis(10)));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..200 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    thread::sleep(Duration::from_millis(50));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value199".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn invalid_options() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_ratio(1.5);
+    assert!(KvStore::open_with(temp_dir.path(), options).is_err());
+    let options = KvStoreOptions::new().max_file_size(0);
+    assert!(KvStore::open_with(temp_dir.path(), options).is_err());
+}
+
+// Concurrent durable writes are committed in groups and must all land.
+#[test]
+fn concurrent_set_with_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..50 {
+                store
+                    .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Only one of several concurrent removes of the same key may succeed.
+#[test]
+fn concurrent_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let barrier = Arc::new(Barrier::new(10));
+    let handles: Vec<_> = (0..10)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.remove("key1".to_owned()).is_ok()
+            })
+        })
+        .collect();
+    let removed = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|&removed| removed)
+        .count();
+    assert_eq!(removed, 1);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Reads racing with writes and compactions should always find the key.
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value0".to_owned())?;
+    }
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    let value = store.get(format!("key{}", i % 10)).unwrap();
+                    assert!(value.unwrap().starts_with("value"));
+                }
+            })
+        })
+        .collect();
+    for iter in 1..500 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    Ok(())
+}
+
+// Log files in the store directory, oldest first.
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<(u64, PathBuf)> = fs::read_dir(dir)
+        .expect("unable to read store directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("db".as_ref()))
+        .map(|path| {
+            let file_no = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            (file_no, path)
+        })
+        .collect();
+    files.sort();
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+// A partially written record at the end of the newest log should be dropped on open.
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let newest = log_files(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&newest)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&newest)?
+        .write_all(&[0x12, 0x34, 0x56, 0x78, 0x40, 0, 0, 0, b'{'])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&newest)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A record failing its checksum in an older log should fail the open.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let oldest = log_files(temp_dir.path()).remove(0);
+    let mut content = fs::read(&oldest)?;
+    let last = content.len() - 3;
+    content[last] ^= 0xff;
+    fs::write(&oldest, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptedLogError(_, _)) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corrupted record was not reported"),
+    }
+}
+
+// A damaged record followed by valid ones in the newest log is not a torn write,
+// so the open should fail rather than cut off the records after it.
+#[test]
+fn detect_corrupted_record_in_newest_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // Every record holds a 13 byte header, a 4 byte key and a 6 byte value,
+    // behind the 5 byte header of the log.
+    let newest = log_files(temp_dir.path()).pop().unwrap();
+    let mut content = fs::read(&newest)?;
+    let len = content.len() as u64;
+    content[5 + 23 + 15] ^= 0xff;
+    fs::write(&newest, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptedLogError(_, _)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corrupted record was not reported"),
+    }
+    assert_eq!(fs::metadata(&newest)?.len(), len);
+    Ok(())
+}
+
+// A value torn by a crash should be dropped even if it holds bytes that look
+// like a record.
+#[test]
+fn recover_torn_write_of_record_like_value() -> Result<()> {
+    // Take a real encoded record from another store's log.
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    other.set("inner".to_owned(), "value".to_owned())?;
+    drop(other);
+    let record = fs::read(log_files(other_dir.path()).pop().unwrap())?[5..].to_vec();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let newest = log_files(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&newest)?.len();
+    let mut value = vec![0; 100];
+    value.extend_from_slice(&record);
+    value.extend_from_slice(&[0; 100]);
+    store.set_bytes(b"key2".to_vec(), value)?;
+    drop(store);
+
+    // Cut the value right after the record it holds.
+    OpenOptions::new()
+        .write(true)
+        .open(&newest)?
+        .set_len(len + 13 + 4 + 100 + record.len() as u64)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&newest)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("inner".to_owned())?, None);
+    Ok(())
+}
+
+// Data directories written with the old JSON log format should still open.
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.db"),
+        r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}{"Rm":"key2"}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Compaction should leave a hint file, and a damaged hint should fall back to replaying the log.
+#[test]
+fn open_with_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let hints: Vec<PathBuf> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    assert_eq!(hints.len(), 1, "expected a single hint file");
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value99".to_owned())
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    let mut content = fs::read(&hints[0])?;
+    let middle = content.len() / 2;
+    content[middle] ^= 0xff;
+    fs::write(&hints[0], content)?;
+    check()
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +602,447 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+fn check_scan<E: KvsEngine>(store: E) -> Result<()> {
+    for key in &["a", "b1", "b2", "b3", "c"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("b2".to_owned())?;
+    let keys = |pairs: std::vec::IntoIter<(String, String)>| {
+        pairs
+            .map(|(key, value)| {
+                assert_eq!(value, format!("value-{}", key));
+                key
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let all = store.scan(.., ScanOptions::new())?;
+    assert_eq!(keys(all), vec!["a", "b1", "b3", "c"]);
+    let range = store.scan("b".to_owned().."c".to_owned(), ScanOptions::new())?;
+    assert_eq!(keys(range), vec!["b1", "b3"]);
+    let options = ScanOptions::new().reverse().limit(2);
+    let last = store.scan("b1".to_owned().., options)?;
+    assert_eq!(keys(last), vec!["c", "b3"]);
+
+    let prefixed = store.scan_prefix("b".to_owned(), ScanOptions::new())?;
+    assert_eq!(keys(prefixed), vec!["b1", "b3"]);
+    let prefixed = store.scan_prefix("b".to_owned(), ScanOptions::new().reverse())?;
+    assert_eq!(keys(prefixed), vec!["b3", "b1"]);
+    let prefixed = store.scan_prefix("".to_owned(), ScanOptions::new().limit(1))?;
+    assert_eq!(keys(prefixed), vec!["a"]);
+    let prefixed = store.scan_prefix("d".to_owned(), ScanOptions::new())?;
+    assert!(keys(prefixed).is_empty());
+    Ok(())
+}
+
+#[test]
+fn kv_store_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_store_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(SledStore::open(temp_dir.path())?)
+}
+
+fn check_write_batch<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("object".to_owned(), "old".to_owned())?;
+    store.set("index:old".to_owned(), "object".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("object".to_owned(), "new".to_owned());
+    batch.remove("index:old".to_owned());
+    batch.set("index:new".to_owned(), "object".to_owned());
+    batch.remove("missing".to_owned());
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+
+    assert_eq!(store.get("object".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("index:old".to_owned())?, None);
+    assert_eq!(
+        store.get("index:new".to_owned())?,
+        Some("object".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn kv_store_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("object".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("index:old".to_owned())?, None);
+    assert_eq!(
+        store.get("index:new".to_owned())?,
+        Some("object".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn sled_store_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(SledStore::open(temp_dir.path())?)
+}
+
+// Readers should never see one write of a batch without the others, however
+// many other writes the batch holds in between.
+#[test]
+fn concurrent_get_during_write_batch() -> Result<()> {
+    const BATCHES: usize = 200;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..BATCHES {
+                let mut batch = WriteBatch::new();
+                for j in 0..50 {
+                    batch.set(format!("pad{}", j), format!("{}", i));
+                }
+                batch.set(format!("index{}", i), format!("object{}", i));
+                for j in 50..100 {
+                    batch.set(format!("pad{}", j), format!("{}", i));
+                }
+                batch.set(format!("object{}", i), "value".to_owned());
+                store.write_batch(batch)?;
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..BATCHES {
+                    // Catch the index entry as soon as it shows up.
+                    while store.get(format!("index{}", i))?.is_none() {}
+                    assert_eq!(
+                        store.get(format!("object{}", i))?,
+                        Some("value".to_owned()),
+                        "index{} was visible without object{}",
+                        i,
+                        i
+                    );
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// A scan should see every write of a batch or none of them.
+#[test]
+fn concurrent_scan_during_write_batch() -> Result<()> {
+    const BATCHES: usize = 200;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..BATCHES {
+                let mut batch = WriteBatch::new();
+                for j in 0..100 {
+                    batch.set(format!("pair{:03}", j), format!("{}", i));
+                }
+                store.write_batch(batch)?;
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let last = format!("{}", BATCHES - 1);
+                loop {
+                    let values: Vec<_> = store
+                        .scan_prefix("pair".to_owned(), ScanOptions::new())?
+                        .map(|(_, value)| value)
+                        .collect();
+                    if let Some(first) = values.first() {
+                        assert_eq!(values.len(), 100, "scan saw part of a batch");
+                        assert!(
+                            values.iter().all(|value| value == first),
+                            "scan saw writes of different batches"
+                        );
+                        if *first == last {
+                            return Ok(());
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// A batch torn by a crash should be discarded as a whole on recovery.
+#[test]
+fn recover_torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Cut into the last record of the batch, leaving the first one intact.
+    let newest = log_files(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&newest)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&newest)?
+        .set_len(len - 4)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+fn check_compare_and_swap<E: KvsEngine>(store: E) -> Result<()> {
+    let outcome = |succeeded, current: Option<&str>| CasOutcome {
+        succeeded,
+        current: current.map(str::to_owned),
+    };
+    let key = || "key1".to_owned();
+    let value = |v: &str| Some(v.to_owned());
+
+    let swapped = store.compare_and_swap(key(), None, value("value1"))?;
+    assert_eq!(swapped, outcome(true, Some("value1")));
+    let swapped = store.compare_and_swap(key(), None, value("value2"))?;
+    assert_eq!(swapped, outcome(false, Some("value1")));
+    let swapped = store.compare_and_swap(key(), value("value2"), value("value3"))?;
+    assert_eq!(swapped, outcome(false, Some("value1")));
+    let swapped = store.compare_and_swap(key(), value("value1"), value("value2"))?;
+    assert_eq!(swapped, outcome(true, Some("value2")));
+    assert_eq!(store.get(key())?, value("value2"));
+
+    let swapped = store.compare_and_swap(key(), value("value2"), None)?;
+    assert_eq!(swapped, outcome(true, None));
+    assert_eq!(store.get(key())?, None);
+    let swapped = store.compare_and_swap(key(), value("value2"), None)?;
+    assert_eq!(swapped, outcome(false, None));
+    Ok(())
+}
+
+#[test]
+fn kv_store_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_store_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(SledStore::open(temp_dir.path())?)
+}
+
+// Increments done through compare-and-swap should never get lost.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let mut current = store.get("counter".to_owned()).unwrap();
+                    loop {
+                        let next = current.as_ref().unwrap().parse::<u64>().unwrap() + 1;
+                        let outcome = store
+                            .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                            .unwrap();
+                        if outcome.succeeded {
+                            break;
+                        }
+                        current = outcome.current;
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+fn check_ttl<E: KvsEngine>(store: E) -> Result<()> {
+    let ttl = Duration::from_millis(200);
+    store.set_with_ttl("session1".to_owned(), "value1".to_owned(), ttl)?;
+    store.set_with_ttl("session2".to_owned(), "value2".to_owned(), ttl)?;
+    store.set_with_ttl("session3".to_owned(), "value3".to_owned(), ttl)?;
+    store.set("session3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("session1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.scan(.., ScanOptions::new())?.count(), 3);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("session1".to_owned())?, None);
+    assert_eq!(store.get("session3".to_owned())?, Some("value3".to_owned()));
+    let keys: Vec<_> = store
+        .scan(.., ScanOptions::new())?
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["session3"]);
+    match store.remove("session1".to_owned()) {
+        Err(KvsError::NotFoundError(_)) => {}
+        other => panic!("unexpected result removing an expired key: {:?}", other),
+    }
+    let swapped = store.compare_and_swap("session2".to_owned(), None, Some("value4".to_owned()))?;
+    assert!(swapped.succeeded);
+    assert_eq!(store.get("session2".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kv_store_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(KvStore::open(temp_dir.path())?)?;
+
+    // Expiries should survive a restart.
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(200);
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_store_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(SledStore::open(temp_dir.path())?)
+}
+
+// Compaction should drop expired keys from the log.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let value = "x".repeat(1000);
+    for key_id in 0..100 {
+        let ttl = Duration::from_millis(1);
+        store.set_with_ttl(format!("session{}", key_id), value.clone(), ttl)?;
+    }
+    let ttl = Duration::from_secs(3600);
+    store.set_with_ttl("long-lived".to_owned(), "value".to_owned(), ttl)?;
+    thread::sleep(Duration::from_millis(10));
+
+    let log_size = || -> u64 {
+        log_files(temp_dir.path())
+            .iter()
+            .map(|path| fs::metadata(path).map(|meta| meta.len()).unwrap_or(0))
+            .sum()
+    };
+    for iter in 0..1000 {
+        if log_size() < 20_000 {
+            break;
+        }
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(log_size() < 20_000, "expired keys were not compacted away");
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session0".to_owned())?, None);
+    assert_eq!(
+        store.get("long-lived".to_owned())?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+fn check_binary_values<E: KvsEngine>(store: E) -> Result<()> {
+    let values: Vec<Vec<u8>> = vec![
+        vec![0xff, 0x00, 0xfe],
+        vec![0xff],
+        vec![0x00, 0x80],
+        Vec::new(),
+    ];
+    for (key_id, value) in values.iter().enumerate() {
+        store.set_bytes(vec![0xfe, key_id as u8], value.clone())?;
+    }
+    for (key_id, value) in values.iter().enumerate() {
+        assert_eq!(
+            store.get_bytes(vec![0xfe, key_id as u8])?,
+            Some(value.clone())
+        );
+    }
+    let scanned: Vec<_> = store
+        .scan_prefix_bytes(vec![0xfe], ScanOptions::new())?
+        .map(|(_, value)| value)
+        .collect();
+    assert_eq!(scanned, values);
+
+    let ttl = Duration::from_secs(3600);
+    store.set_bytes_with_ttl(b"expiring".to_vec(), vec![0xff, 0x01], ttl)?;
+    assert_eq!(
+        store.get_bytes(b"expiring".to_vec())?,
+        Some(vec![0xff, 0x01])
+    );
+
+    // The string API refuses to return bytes that are not UTF-8.
+    match store.get(String::from_utf8_lossy(&[0xfe, 0]).into_owned()) {
+        Ok(None) => {}
+        other => panic!("unexpected result for a lossy key: {:?}", other),
+    }
+    store.set_bytes(b"binary".to_vec(), vec![0xc3, 0x28])?;
+    match store.get("binary".to_owned()) {
+        Err(KvsError::StringParseError(_)) => {}
+        other => panic!("unexpected result reading a binary value: {:?}", other),
+    }
+    store.remove_bytes(vec![0xfe, 0])?;
+    assert_eq!(store.get_bytes(vec![0xfe, 0])?, None);
+    Ok(())
+}
+
+#[test]
+fn kv_store_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary_values(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(vec![0xfe, 1])?, Some(vec![0xff]));
+    assert_eq!(store.get_bytes(vec![0xfe, 0])?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_store_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary_values(SledStore::open(temp_dir.path())?)
+}
@@ -0,0 +1,304 @@
+use kvs::{
+    BusyPolicy, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, NaiveThreadPool, Protocol,
+    Result, SharedQueueThreadPool, ShutdownHandle, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+type ServerThread = JoinHandle<Result<()>>;
+
+// Returns a local address no one listens on.
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+// Starts a server for the store in `path` on a free address, set up further
+// by `configure`. Returns the address, the handle that stops the server and
+// the thread it runs on.
+fn start_server<P, F>(
+    path: &Path,
+    pool: P,
+    configure: F,
+) -> Result<(String, ShutdownHandle, ServerThread)>
+where
+    P: ThreadPool + Send + 'static,
+    F: FnOnce(KvsServer<KvStore, P>) -> KvsServer<KvStore, P>,
+{
+    let addr = free_addr();
+    let store = KvStore::open(path)?;
+    let server = KvsServer::new(addr.clone(), store, Logger::root(Discard, o!()), pool)?;
+    let mut server = configure(server);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.start());
+    Ok((addr, handle, server))
+}
+
+fn connect(addr: &str) -> KvsClient {
+    for _ in 0..100 {
+        if let Ok(client) = KvsClient::connect(addr) {
+            return client;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server at {} did not start", addr);
+}
+
+// Shutting down should stop the server even with connections open, keep what
+// was written, and free the address for another server.
+#[test]
+fn shutdown_stops_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for round in 0..2 {
+        let pool = NaiveThreadPool::new(4)?;
+        let (addr, handle, server) = start_server(temp_dir.path(), pool, |server| server)?;
+
+        let mut client = connect(&addr);
+        if round == 0 {
+            client.set("key1", "value1")?;
+        }
+        assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+        let mut idle = connect(&addr);
+
+        handle.shutdown();
+        server.join().unwrap()?;
+        assert!(idle.get("key1").is_err());
+        assert!(TcpStream::connect(&addr).is_err());
+        TcpListener::bind(&addr)?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Requests a client has already sent are answered before its connection is
+// closed.
+#[test]
+fn shutdown_drains_sent_requests() -> Result<()> {
+    let http_addr = free_addr();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(4)?;
+    let (addr, handle, server) = start_server(temp_dir.path(), pool, |server| {
+        server.http_addr(http_addr.clone())
+    })?;
+    connect(&addr);
+
+    let mut stream = TcpStream::connect(&http_addr)?;
+    let mut requests = String::new();
+    for i in 0..100 {
+        let value = format!("value{}", i);
+        requests += &format!(
+            "PUT /keys/key{} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            i,
+            value.len(),
+            value
+        );
+    }
+    stream.write_all(requests.as_bytes())?;
+    let mut status = [0; 12];
+    stream.read_exact(&mut status)?;
+    assert_eq!(&status, b"HTTP/1.1 204");
+
+    handle.shutdown();
+    let mut responses = String::new();
+    stream.read_to_string(&mut responses)?;
+    assert_eq!(responses.matches("HTTP/1.1 204").count(), 99);
+    server.join().unwrap()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Clients that do not read their responses are cut off once the drain
+// timeout is over, instead of holding the shutdown up.
+#[test]
+fn shutdown_cuts_off_clients_not_reading() -> Result<()> {
+    let http_addr = free_addr();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(4)?;
+    let (addr, handle, server) = start_server(temp_dir.path(), pool, |server| {
+        server
+            .http_addr(http_addr.clone())
+            .drain_timeout(Duration::from_millis(200))
+    })?;
+    connect(&addr).set("key1", &"x".repeat(1024 * 1024))?;
+
+    // Far more responses than the socket buffers hold.
+    let mut stream = TcpStream::connect(&http_addr)?;
+    stream.write_all("GET /keys/key1 HTTP/1.1\r\n\r\n".repeat(100).as_bytes())?;
+    thread::sleep(Duration::from_millis(200));
+
+    handle.shutdown();
+    for _ in 0..1000 {
+        if server.is_finished() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(server.is_finished(), "server did not stop");
+    drop(stream);
+    server.join().unwrap()
+}
+
+// Errors keep their kind across the connection.
+#[test]
+fn errors_keep_their_kind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(4)?;
+    let (addr, handle, server) = start_server(temp_dir.path(), pool, |server| server)?;
+
+    let mut client = connect(&addr);
+    match client.remove("key1") {
+        Err(KvsError::NotFoundError(key)) => assert_eq!(key, "key1"),
+        res => panic!("expected a not found error, got {:?}", res),
+    }
+    client.set("key1", "value1")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// Clients agree on features with the server, and those that do not handshake
+// are told why they are not served.
+#[test]
+fn handshake() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = NaiveThreadPool::new(4)?;
+    let (addr, handle, server) = start_server(temp_dir.path(), pool, |server| server)?;
+
+    let client = connect(&addr);
+    for feature in &["batch", "cas", "scan", "ttl"] {
+        assert!(client.supports(feature));
+    }
+    assert!(!client.supports("compression"));
+
+    // A frame that is not a hello, as sent by clients older than the handshake.
+    let mut stream = TcpStream::connect(&addr)?;
+    stream.write_all(&[0, 0, 0, 16])?;
+    stream.write_all(&[0; 16])?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    assert!(String::from_utf8_lossy(&reply).contains("Expected a hello"));
+
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// Starts a server with a single thread, where `queue_len` connections can wait
+// for it.
+fn start_saturable(
+    queue_len: usize,
+    protocol: Protocol,
+    policy: BusyPolicy,
+) -> Result<(String, ShutdownHandle, ServerThread, TempDir)> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::bounded(1, queue_len)?;
+    let (addr, handle, server) = start_server(temp_dir.path(), pool, |server| {
+        server.protocol(protocol).busy_policy(policy)
+    })?;
+    Ok((addr, handle, server, temp_dir))
+}
+
+#[test]
+fn busy_server_blocks() -> Result<()> {
+    let (addr, handle, server, _temp_dir) = start_saturable(0, Protocol::Kvs, BusyPolicy::Block)?;
+
+    let mut first = connect(&addr);
+    let waiting =
+        thread::spawn(move || -> Result<Option<String>> { KvsClient::connect(&addr)?.get("key1") });
+    first.set("key1", "value1")?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(!waiting.is_finished());
+    drop(first);
+    assert_eq!(waiting.join().unwrap()?, Some("value1".to_owned()));
+
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+#[test]
+fn busy_server_rejects() -> Result<()> {
+    let (addr, handle, server, _temp_dir) = start_saturable(0, Protocol::Kvs, BusyPolicy::Reject)?;
+
+    let first = connect(&addr);
+    match KvsClient::connect(&addr) {
+        Err(KvsError::ServerBusyError) => {}
+        res => panic!("expected a busy server, got {:?}", res.map(|_| ())),
+    }
+    drop(first);
+    connect_when_free(&addr);
+
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+#[test]
+fn busy_server_sheds_oldest() -> Result<()> {
+    let (addr, handle, server, _temp_dir) =
+        start_saturable(1, Protocol::Kvs, BusyPolicy::ShedOldest)?;
+
+    let first = connect(&addr);
+    let oldest = {
+        let addr = addr.clone();
+        thread::spawn(move || KvsClient::connect(&addr).map(|_| ()))
+    };
+    thread::sleep(Duration::from_millis(200));
+    let newest =
+        thread::spawn(move || -> Result<Option<String>> { KvsClient::connect(&addr)?.get("key1") });
+    match oldest.join().unwrap() {
+        Err(KvsError::ServerBusyError) => {}
+        res => panic!("expected a busy server, got {:?}", res),
+    }
+    drop(first);
+    assert_eq!(newest.join().unwrap()?, None);
+
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+#[test]
+fn busy_resp_server_rejects() -> Result<()> {
+    let (addr, handle, server, _temp_dir) = start_saturable(0, Protocol::Resp, BusyPolicy::Reject)?;
+
+    let mut first = loop {
+        if let Ok(stream) = TcpStream::connect(&addr) {
+            break stream;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    first.write_all(b"PING\r\n")?;
+    let mut pong = [0; 7];
+    first.read_exact(&mut pong)?;
+    assert_eq!(&pong, b"+PONG\r\n");
+
+    let mut second = TcpStream::connect(&addr)?;
+    let mut reply = String::new();
+    second.read_to_string(&mut reply)?;
+    assert_eq!(reply, "-ERR max number of clients reached\r\n");
+
+    drop(first);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// Connects once the server has a thread free for the connection.
+fn connect_when_free(addr: &str) -> KvsClient {
+    for _ in 0..100 {
+        match KvsClient::connect(addr) {
+            Ok(client) => return client,
+            Err(KvsError::ServerBusyError) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => panic!("unable to connect: {}", e),
+        }
+    }
+    panic!("server at {} stayed busy", addr);
+}
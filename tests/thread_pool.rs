@@ -1,8 +1,11 @@
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Barrier};
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -46,6 +49,152 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+// A pool of one thread and one queued job should refuse a third job until the
+// first two finish.
+fn try_spawn_saturated<P: ThreadPool>() -> Result<()> {
+    let pool = P::bounded(1, 1)?;
+    let mut releases = Vec::new();
+    for _ in 0..2 {
+        let (release, released) = mpsc::channel::<()>();
+        pool.spawn(move || {
+            let _ = released.recv();
+        });
+        releases.push(release);
+    }
+    match pool.try_spawn(|| {}) {
+        Err(KvsError::PoolSaturatedError) => {}
+        res => panic!("expected a saturated pool, got {:?}", res),
+    }
+
+    drop(releases);
+    for _ in 0..1000 {
+        if pool.try_spawn(|| {}).is_ok() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the pool stayed saturated");
+}
+
+// Handles should return what jobs return, and what they panic with.
+fn spawn_with_handle_results<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let handles: Vec<_> = (0..20)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), i * 2);
+    }
+
+    let handle = pool.spawn_with_handle(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("job failed");
+    });
+    let payload = handle.join().unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+    Ok(())
+}
+
+// Scoped jobs should be able to borrow from the stack, and a panicking one
+// should make the scope panic once the others are done.
+fn scope_borrows<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let numbers: Vec<usize> = (0..1000).collect();
+    let mut sums = [0; 10];
+    pool.scope(|scope| {
+        for (chunk, sum) in numbers.chunks(100).zip(sums.iter_mut()) {
+            scope.spawn(move || *sum = chunk.iter().sum());
+        }
+    });
+    assert_eq!(sums.iter().sum::<usize>(), numbers.iter().sum::<usize>());
+
+    let done = AtomicUsize::new(0);
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        pool.scope(|scope| {
+            scope.spawn(|| {
+                panic_control::disable_hook_in_current_thread();
+                panic!();
+            });
+            for _ in 0..10 {
+                scope.spawn(|| {
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        })
+    }));
+    assert!(res.is_err());
+    assert_eq!(done.load(Ordering::SeqCst), 10);
+    Ok(())
+}
+
+// Stopping a pool, by `shutdown` or by dropping it, should run the jobs still
+// waiting for a thread before the pool's threads exit.
+fn shutdown_runs_queued<P: ThreadPool>() -> Result<()> {
+    for shutdown in &[true, false] {
+        let pool = P::new(1)?;
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            pool.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        if *shutdown {
+            pool.shutdown(Duration::from_secs(10))?;
+        } else {
+            drop(pool);
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+    }
+    Ok(())
+}
+
+// `shutdown_now` should drop the jobs waiting behind a running one.
+fn shutdown_now_drops_queued<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(1)?;
+    let (started, starts) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        let _ = released.recv();
+    });
+    starts.recv().unwrap();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(release);
+    });
+    pool.shutdown_now(Duration::from_secs(10))?;
+    assert_eq!(counter.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+// A shutdown should give up on a job that runs past the timeout.
+fn shutdown_times_out<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (started, starts) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        let _ = released.recv();
+    });
+    starts.recv().unwrap();
+    match pool.shutdown(Duration::from_millis(100)) {
+        Err(KvsError::PoolShutdownTimeoutError(_)) => {}
+        res => panic!("expected a shutdown timeout, got {:?}", res),
+    }
+    drop(release);
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -68,3 +217,125 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+// After its jobs panicked, the pool should still run as many jobs at once as
+// it has threads.
+#[test]
+fn shared_queue_thread_pool_keeps_its_size() -> Result<()> {
+    const SIZE: usize = 4;
+
+    let pool = SharedQueueThreadPool::new(SIZE)?;
+    for _ in 0..100 {
+        pool.spawn(move || {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })
+    }
+
+    let barrier = Arc::new(Barrier::new(SIZE));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..SIZE {
+        let barrier = Arc::clone(&barrier);
+        let sender = sender.clone();
+        pool.spawn(move || {
+            barrier.wait();
+            sender.send(()).unwrap();
+        })
+    }
+    for _ in 0..SIZE {
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the pool lost threads");
+    }
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_try_spawn_saturated() -> Result<()> {
+    try_spawn_saturated::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_try_spawn_saturated() -> Result<()> {
+    try_spawn_saturated::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_try_spawn_saturated() -> Result<()> {
+    try_spawn_saturated::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_results::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_results::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_results::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_scope() -> Result<()> {
+    scope_borrows::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_scope() -> Result<()> {
+    scope_borrows::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_scope() -> Result<()> {
+    scope_borrows::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_shutdown_runs_queued() -> Result<()> {
+    shutdown_runs_queued::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_runs_queued() -> Result<()> {
+    shutdown_runs_queued::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_shutdown_runs_queued() -> Result<()> {
+    shutdown_runs_queued::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_now_drops_queued() -> Result<()> {
+    shutdown_now_drops_queued::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_shutdown_now_drops_queued() -> Result<()> {
+    shutdown_now_drops_queued::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_shutdown_times_out() -> Result<()> {
+    shutdown_times_out::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_times_out() -> Result<()> {
+    shutdown_times_out::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_shutdown_times_out() -> Result<()> {
+    shutdown_times_out::<RayonThreadPool>()
+}